@@ -0,0 +1,364 @@
+use std::collections::{BTreeMap, BTreeSet};
+use std::fs::File;
+use std::io::prelude::*;
+
+use get_opcode;
+use debuginfo::{self, DebugInfo};
+use disasm::{self, Instruction};
+
+// How control leaves a basic block.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Exit {
+    // Runs on into the next block.
+    Fallthrough(usize),
+    Jump(usize),
+    // A skip goes to `next` or, when it skips, to `skipped`.
+    Skip { next: usize, skipped: usize },
+    Return,
+    // BNNN, whose target depends on V0.
+    Indirect,
+    // An opcode that isn't an instruction, or a jump out of the ROM, so
+    // the analysis can't follow it.
+    Stop
+}
+
+// A run of instructions only entered at its start.
+#[derive(Debug, Clone, PartialEq)]
+pub struct Block {
+    pub start: usize,
+    // The address after the last instruction.
+    pub end: usize,
+    pub exit: Exit,
+    // Subroutines called from the block.
+    pub calls: Vec<usize>
+}
+
+// Something the analysis couldn't be sure of, by the address it happened.
+#[derive(Debug, Clone, PartialEq)]
+pub enum Note {
+    // LD B or LD [I] writing into code, with the address written.
+    SelfModifying(usize, usize),
+    // LD B or LD [I] with an I the analysis couldn't work out, so it may
+    // write into code.
+    UnknownStore(usize),
+    IndirectJump(usize),
+    // A jump or call to an address outside the ROM.
+    OutsideRom(usize, usize),
+    // Code running into an opcode that isn't an instruction.
+    InvalidOpcode(usize, u16)
+}
+
+// The result of following every path through a ROM from 0x200, so code can
+// be told apart from the sprites and other data between it.
+pub struct Analysis {
+    pub rom: Vec<u8>,
+    pub blocks: BTreeMap<usize, Block>,
+    pub subroutines: BTreeSet<usize>,
+    pub notes: Vec<Note>,
+    // The address of each instruction found.
+    instructions: BTreeSet<usize>
+}
+
+pub fn is_skip(instruction: Instruction) -> bool {
+    matches!(instruction, Instruction::SkipEqual(..) | Instruction::SkipNotEqual(..) | Instruction::SkipEqualReg(..) |
+             Instruction::SkipNotEqualReg(..) | Instruction::SkipKey(_) | Instruction::SkipNotKey(_))
+}
+
+// Unknown opcodes and 0NNN, which ran machine code on the original
+// machines, are almost always data that control has run into.
+fn is_invalid(instruction: Instruction) -> bool {
+    matches!(instruction, Instruction::Unknown(_) | Instruction::System(_))
+}
+
+pub fn analyze(rom: &[u8]) -> Analysis {
+    let end = 0x200 + rom.len();
+    let in_rom = |address: usize| address >= 0x200 && address + 1 < end;
+    let opcode_at = |address: usize| get_opcode(address - 0x200, rom);
+
+    let mut instructions = BTreeSet::new();
+    let mut leaders = BTreeSet::new();
+    let mut subroutines = BTreeSet::new();
+    let mut notes = Vec::new();
+    // Stores to check against the code once it's all found: the address of
+    // the store and the memory it writes.
+    let mut stores = Vec::new();
+
+    // Follow each path, carrying the value of I where it's known from an
+    // LD I on the way. Paths meeting code already seen stop there, so I is
+    // whatever the first path to reach an instruction had.
+    let mut pending = vec![(0x200, None)];
+    leaders.insert(0x200);
+    while let Some((start, mut index)) = pending.pop() {
+        let mut address = start;
+        while in_rom(address) {
+            if instructions.contains(&address) {
+                // The path runs into code found before, so a block starts
+                // there.
+                leaders.insert(address);
+                break;
+            }
+            let opcode = opcode_at(address);
+            let instruction = disasm::decode(opcode);
+            if is_invalid(instruction) {
+                notes.push(Note::InvalidOpcode(address, opcode));
+                break;
+            }
+            instructions.insert(address);
+            let next = address + 2;
+            match instruction {
+                Instruction::LoadIndex(nnn) => index = Some(nnn as usize),
+                Instruction::AddIndex(_) | Instruction::Font(_) => index = None,
+                Instruction::Bcd(_) | Instruction::Store(_) => {
+                    match index {
+                        Some(index) => stores.push((address, instruction.memory_writes(index))),
+                        None => notes.push(Note::UnknownStore(address))
+                    }
+                }
+                _ => ()
+            }
+            match instruction {
+                Instruction::Jump(nnn) | Instruction::Call(nnn) => {
+                    let target = nnn as usize;
+                    if let Instruction::Call(_) = instruction {
+                        subroutines.insert(target);
+                    }
+                    if in_rom(target) {
+                        leaders.insert(target);
+                        pending.push((target, index));
+                    } else {
+                        notes.push(Note::OutsideRom(address, target));
+                    }
+                    if let Instruction::Jump(_) = instruction {
+                        break;
+                    }
+                    // The subroutine may have changed I by the time it
+                    // returns.
+                    index = None;
+                }
+                Instruction::Return => break,
+                Instruction::JumpOffset(_) => {
+                    notes.push(Note::IndirectJump(address));
+                    break;
+                }
+                _ if is_skip(instruction) => {
+                    leaders.insert(next);
+                    leaders.insert(next + 2);
+                    pending.push((next + 2, index));
+                }
+                _ => ()
+            }
+            address = next;
+        }
+    }
+
+    // Split the code into blocks at each leader and after each instruction
+    // that doesn't simply run on.
+    let mut blocks = BTreeMap::new();
+    for &start in leaders.iter().filter(|address| instructions.contains(address)) {
+        let mut block = Block { start, end: start, exit: Exit::Stop, calls: Vec::new() };
+        let mut address = start;
+        loop {
+            let instruction = disasm::decode(opcode_at(address));
+            let next = address + 2;
+            block.end = next;
+            block.exit = match instruction {
+                Instruction::Jump(nnn) if in_rom(nnn as usize) => Exit::Jump(nnn as usize),
+                Instruction::Jump(_) => Exit::Stop,
+                Instruction::Return => Exit::Return,
+                Instruction::JumpOffset(_) => Exit::Indirect,
+                _ if is_skip(instruction) => Exit::Skip { next, skipped: next + 2 },
+                _ => {
+                    if let Instruction::Call(nnn) = instruction {
+                        block.calls.push(nnn as usize);
+                    }
+                    if !instructions.contains(&next) {
+                        Exit::Stop
+                    } else if leaders.contains(&next) {
+                        Exit::Fallthrough(next)
+                    } else {
+                        address = next;
+                        continue;
+                    }
+                }
+            };
+            break;
+        }
+        blocks.insert(start, block);
+    }
+
+    let mut analysis = Analysis { rom: rom.to_vec(), blocks, subroutines, notes, instructions };
+    for (address, mut writes) in stores {
+        if let Some(target) = writes.find(|&target| analysis.is_code(target)) {
+            analysis.notes.push(Note::SelfModifying(address, target));
+        }
+    }
+    analysis.notes.sort_by_key(|note| match *note {
+        Note::SelfModifying(address, _) | Note::UnknownStore(address) | Note::IndirectJump(address) |
+        Note::OutsideRom(address, _) | Note::InvalidOpcode(address, _) => address
+    });
+    analysis
+}
+
+impl Analysis {
+    pub fn is_instruction(&self, address: usize) -> bool {
+        self.instructions.contains(&address)
+    }
+
+    // Whether a byte is part of an instruction.
+    pub fn is_code(&self, address: usize) -> bool {
+        self.is_instruction(address) || (address > 0 && self.is_instruction(address - 1))
+    }
+
+    pub fn opcode(&self, address: usize) -> u16 {
+        get_opcode(address - 0x200, &self.rom)
+    }
+
+    fn block_name(&self, address: usize, debug_info: &DebugInfo) -> String {
+        match debug_info.label_at(address) {
+            Some(label) => label.to_string(),
+            None if self.subroutines.contains(&address) => debug_info.subroutine_name(address),
+            None => format!("block_{:03X}", address)
+        }
+    }
+
+    // The ROM with code disassembled by block and data as bytes.
+    pub fn listing(&self, debug_info: &DebugInfo) -> String {
+        let end = 0x200 + self.rom.len();
+        let code = (0x200..end).filter(|&address| self.is_code(address)).count();
+        let mut listing = format!("; {} bytes: {} code in {} blocks, {} data\n",
+                                  self.rom.len(), code, self.blocks.len(), self.rom.len() - code);
+        let mut address = 0x200;
+        while address < end {
+            if self.is_instruction(address) {
+                if self.blocks.contains_key(&address) {
+                    listing.push_str(&format!("{}:\n", self.block_name(address, debug_info)));
+                }
+                let opcode = self.opcode(address);
+                listing.push_str(&format!("  {:03X}  {:04X}  {}\n", address, opcode,
+                                          disasm::disassemble_with_labels(opcode, debug_info)));
+                address += 2;
+                continue;
+            }
+            // Up to 8 bytes of data a line, stopping where code starts.
+            let mut bytes = Vec::new();
+            while address + bytes.len() < end && bytes.len() < 8 && !self.is_instruction(address + bytes.len()) {
+                bytes.push(format!("#{:02X}", self.rom[address + bytes.len() - 0x200]));
+            }
+            listing.push_str(&format!("  {:03X}        DB {}\n", address, bytes.join(", ")));
+            address += bytes.len();
+        }
+        for note in &self.notes {
+            listing.push_str(&format!("; {}\n", describe_note(note, debug_info)));
+        }
+        listing
+    }
+
+    // The control flow graph in Graphviz DOT format, one node per block.
+    // Jumps and fallthroughs are solid edges, skips are labelled and calls
+    // are dashed.
+    pub fn dot(&self, debug_info: &DebugInfo) -> String {
+        let mut dot = String::from("digraph rom {\n    node [shape=box, fontname=\"monospace\"];\n");
+        for block in self.blocks.values() {
+            let mut label = format!("{}\\l", self.block_name(block.start, debug_info));
+            for address in (block.start..block.end).step_by(2) {
+                let text = disasm::disassemble_with_labels(self.opcode(address), debug_info);
+                label.push_str(&format!("{:03X}  {}\\l", address, text.replace('"', "\\\"")));
+            }
+            dot.push_str(&format!("    b{:03X} [label=\"{}\"];\n", block.start, label));
+        }
+        for block in self.blocks.values() {
+            match block.exit {
+                Exit::Fallthrough(next) | Exit::Jump(next) => {
+                    dot.push_str(&format!("    b{:03X} -> b{:03X};\n", block.start, next));
+                }
+                Exit::Skip { next, skipped } => {
+                    for &(target, label) in &[(next, "no skip"), (skipped, "skip")] {
+                        if self.blocks.contains_key(&target) {
+                            dot.push_str(&format!("    b{:03X} -> b{:03X} [label=\"{}\"];\n", block.start, target, label));
+                        }
+                    }
+                }
+                Exit::Return | Exit::Indirect | Exit::Stop => ()
+            }
+            for &call in &block.calls {
+                if self.blocks.contains_key(&call) {
+                    dot.push_str(&format!("    b{:03X} -> b{:03X} [style=dashed];\n", block.start, call));
+                }
+            }
+        }
+        dot.push_str("}\n");
+        dot
+    }
+}
+
+pub fn describe_note(note: &Note, debug_info: &DebugInfo) -> String {
+    match *note {
+        Note::SelfModifying(address, target) =>
+            format!("{}: writes to code at {}", debug_info.describe(address), debug_info.describe(target)),
+        Note::UnknownStore(address) =>
+            format!("{}: writes through an I that isn't known, which may be into code", debug_info.describe(address)),
+        Note::IndirectJump(address) =>
+            format!("{}: jumps by V0, so the code it reaches may be listed as data", debug_info.describe(address)),
+        Note::OutsideRom(address, target) =>
+            format!("{}: goes to 0x{:03X}, outside the ROM", debug_info.describe(address), target),
+        Note::InvalidOpcode(address, opcode) =>
+            format!("{}: runs into {:04X}, which isn't an instruction", debug_info.describe(address), opcode)
+    }
+}
+
+pub fn read_rom(path: &str) -> Result<Vec<u8>, String> {
+    let mut rom = Vec::new();
+    match File::open(path) {
+        Ok(mut f) => {
+            if let Err(e) = f.read_to_end(&mut rom) {
+                return Err(format!("Could not read ROM '{}': {}", path, e));
+            }
+        }
+        Err(e) => return Err(format!("Could not open ROM '{}': {}", path, e))
+    }
+    Ok(rom)
+}
+
+pub fn analyze_command(args: &[String]) -> Result<(), String> {
+    let mut dot_path = None;
+    let mut debug_info_path = None;
+    let mut rom_path = None;
+    let mut i = 0;
+    while i < args.len() {
+        match &args[i][..] {
+            "--dot" | "--debug-info" => {
+                let value = match args.get(i + 1) {
+                    Some(value) => value.clone(),
+                    None => return Err(format!("{} needs a file", args[i]))
+                };
+                if args[i] == "--dot" {
+                    dot_path = Some(value);
+                } else {
+                    debug_info_path = Some(value);
+                }
+                i += 1;
+            }
+            _ if rom_path.is_none() => rom_path = Some(args[i].clone()),
+            _ => return Err("Usage: rust_chip8 analyze [--dot <file>] [--debug-info <file>] <rom>".to_string())
+        }
+        i += 1;
+    }
+    let rom_path = match rom_path {
+        Some(path) => path,
+        None => return Err("Usage: rust_chip8 analyze [--dot <file>] [--debug-info <file>] <rom>".to_string())
+    };
+    let rom = read_rom(&rom_path)?;
+    let debug_info = match debug_info_path {
+        Some(path) => debuginfo::load_debug_info(&path)?,
+        None => Default::default()
+    };
+    let analysis = analyze(&rom);
+    print!("{}", analysis.listing(&debug_info));
+    if let Some(path) = dot_path {
+        let result = File::create(&path).and_then(|mut f| f.write_all(analysis.dot(&debug_info).as_bytes()));
+        if let Err(e) = result {
+            return Err(format!("Could not write '{}': {}", path, e));
+        }
+    }
+    Ok(())
+}
@@ -0,0 +1,121 @@
+use std::time::Instant;
+
+use {Chip8State, CHIP8_FONTSET, init_state, update_timers};
+use analysis;
+use config;
+use engine::Engine;
+use monitor::Monitors;
+
+// Instructions run per sample, and samples taken after a warm-up run.
+const DEFAULT_CYCLES: u64 = 5_000_000;
+const DEFAULT_SAMPLES: usize = 5;
+
+// Instructions run between timer ticks, so delay loops still end.
+const CYCLES_PER_FRAME: u32 = 1000;
+
+const ENGINES: [(&str, Engine); 3] = [
+    ("interpreter", Engine::Interpreter),
+    ("predecoded", Engine::Predecoded),
+    ("cached", Engine::Cached)
+];
+
+// Programs run when no ROM is given, each looping forever over one kind
+// of work.
+pub const WORKLOADS: [(&str, &[u8]); 3] = [
+    // Counting, copying and XOR in registers, with a skip and a jump back.
+    ("alu", &[0x60, 0x00, 0x61, 0x00, 0x70, 0x01, 0x82, 0x10, 0x82, 0x03,
+              0x30, 0x00, 0x12, 0x04, 0x71, 0x01, 0x12, 0x04]),
+    // Tiling the screen with an 8x8 sprite, then clearing it.
+    ("draw", &[0x00, 0xE0, 0xA2, 0x1A, 0x60, 0x00, 0x61, 0x00, 0xD0, 0x18,
+               0x70, 0x08, 0x30, 0x40, 0x12, 0x08, 0x60, 0x00, 0x71, 0x08,
+               0x31, 0x20, 0x12, 0x08, 0x12, 0x00,
+               0xFF, 0x81, 0xBD, 0xA5, 0xA5, 0xBD, 0x81, 0xFF]),
+    // Converting a counter to decimal, then storing and loading the digits.
+    ("memory", &[0xA3, 0x00, 0x73, 0x01, 0xF3, 0x33, 0xF2, 0x55, 0xF2, 0x65, 0x12, 0x02])
+];
+
+// Runs `rom` headless for `cycles` instructions with `engine`.
+pub fn run(rom: &[u8], engine: Engine, cycles: u64) -> Chip8State {
+    let mut state: Chip8State = Default::default();
+    let mut memory = vec![0u8; 4096];
+    init_state(&mut state);
+    memory[..CHIP8_FONTSET.len()].copy_from_slice(&CHIP8_FONTSET);
+    memory[0x200..0x200 + rom.len()].copy_from_slice(rom);
+    let mut monitors: Monitors = Default::default();
+    monitors.set_engine(engine);
+
+    let mut remaining = cycles;
+    while remaining > 0 {
+        let frame = ::std::cmp::min(remaining, u64::from(CYCLES_PER_FRAME)) as u32;
+        monitors.run(&mut state, &mut memory, frame, |_| ());
+        update_timers(&mut state);
+        remaining -= u64::from(frame);
+    }
+    state
+}
+
+// How many instructions a second `rom` runs at.
+pub fn measure(rom: &[u8], engine: Engine, cycles: u64) -> f64 {
+    let start = Instant::now();
+    run(rom, engine, cycles);
+    let seconds = start.elapsed().as_secs_f64();
+    if seconds > 0.0 { cycles as f64 / seconds } else { 0.0 }
+}
+
+// The median, lowest and highest of some rates.
+pub fn summarize(rates: &[f64]) -> (f64, f64, f64) {
+    let mut sorted = rates.to_vec();
+    sorted.sort_by(|a, b| a.partial_cmp(b).unwrap());
+    // The middle one, or the mean of the middle two.
+    let median = (sorted[(sorted.len() - 1) / 2] + sorted[sorted.len() / 2]) / 2.0;
+    (median, sorted[0], sorted[sorted.len() - 1])
+}
+
+pub fn bench_command(args: &[String]) -> Result<(), String> {
+    let usage = "Usage: rust_chip8 bench [--cycles <n>] [--samples <n>] [rom]";
+    let mut cycles = DEFAULT_CYCLES;
+    let mut samples = DEFAULT_SAMPLES;
+    let mut rom_path = None;
+    let mut i = 0;
+    while i < args.len() {
+        match &args[i][..] {
+            "--cycles" | "--samples" => {
+                let value = match args.get(i + 1).and_then(|value| config::parse_number(value)) {
+                    Some(value) if value > 0 => value,
+                    _ => return Err(format!("{} needs a number above 0", args[i]))
+                };
+                if args[i] == "--cycles" {
+                    cycles = value as u64;
+                } else {
+                    samples = value as usize;
+                }
+                i += 1;
+            }
+            _ if rom_path.is_none() => rom_path = Some(args[i].clone()),
+            _ => return Err(usage.to_string())
+        }
+        i += 1;
+    }
+    let roms = match rom_path {
+        Some(path) => vec![(path.clone(), analysis::read_rom(&path)?)],
+        None => WORKLOADS.iter().map(|&(name, rom)| (name.to_string(), rom.to_vec())).collect()
+    };
+
+    println!("{} samples of {} instructions each, in millions of instructions a second", samples, cycles);
+    println!("{:<16} {:<12} {:>8} {:>8} {:>8} {:>7}", "workload", "engine", "median", "min", "max", "speed");
+    for (name, rom) in &roms {
+        let mut baseline = 0.0;
+        for &(engine_name, engine) in ENGINES.iter() {
+            // Warm up caches and clocks before taking samples.
+            measure(rom, engine, cycles);
+            let rates: Vec<f64> = (0..samples).map(|_| measure(rom, engine, cycles)).collect();
+            let (median, min, max) = summarize(&rates);
+            if engine == Engine::Interpreter {
+                baseline = median;
+            }
+            println!("{:<16} {:<12} {:>8.2} {:>8.2} {:>8.2} {:>6.2}x", name, engine_name,
+                     median / 1e6, min / 1e6, max / 1e6, if baseline > 0.0 { median / baseline } else { 0.0 });
+        }
+    }
+    Ok(())
+}
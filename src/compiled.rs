@@ -0,0 +1,10 @@
+// Code compiled ahead of time from a ROM, which `rust_chip8 recompile`
+// writes over this file. Built with a ROM compiled in, the emulator runs it
+// when no other ROM is given. As it stands nothing is compiled in.
+use Chip8State;
+
+pub const ROM: &[u8] = &[];
+
+pub fn run(_state: &mut Chip8State, _memory: &mut Vec<u8>, _budget: &mut u32) -> Option<bool> {
+    None
+}
@@ -0,0 +1,649 @@
+use std::collections::HashMap;
+use std::fs::File;
+use std::io::prelude::*;
+
+use engine;
+use engine::Engine;
+use keymap;
+use keymap::Keymap;
+use palette;
+use palette::Palette;
+use quirks;
+use quirks::Quirks;
+use render;
+use render::DisplayMode;
+use trace;
+use trace::{TraceFilter, TraceFormat};
+use tui;
+use tui::Glyphs;
+
+pub const DEFAULT_ROM_PATH: &str = "c:\\emu\\chip8\\BRIX.";
+const DEFAULT_CONFIG_PATH: &str = "chip8.toml";
+
+#[derive(Debug, Clone, PartialEq)]
+pub enum ConfigValue {
+    Str(String),
+    Int(i64),
+    Float(f64),
+    Bool(bool),
+    List(Vec<ConfigValue>)
+}
+
+// A small subset of TOML: [section] headers (dotted and quoted names are
+// allowed), key = value pairs, # comments, and string, integer, boolean and
+// single line array values. Keys are stored flattened as "section.key".
+#[derive(Debug, Default)]
+pub struct ConfigFile {
+    pub entries: HashMap<String, ConfigValue>
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Frontend {
+    Window,
+    Terminal,
+    // No display or input, running as fast as possible, for movie playback
+    // and testing.
+    Headless
+}
+
+// Options that take no value.
+const SWITCHES: [&str; 2] = ["verify", "no-gamepad"];
+
+#[derive(Debug)]
+pub struct Options {
+    pub rom_path: String,
+    pub frontend: Frontend,
+    pub palette: Palette,
+    pub display_mode: DisplayMode,
+    // Fraction of brightness a dark pixel loses each frame in phosphor mode.
+    pub phosphor_decay: f32,
+    // Least space in window pixels around the screen, and its color if not
+    // the background's.
+    pub border: u32,
+    pub border_color: Option<[u8; 3]>,
+    pub cycles_per_frame: u32,
+    // How instructions are run when nothing needs to watch each one.
+    pub engine: Engine,
+    pub terminal_glyphs: Glyphs,
+    // How long a terminal key stays down after its last repeat.
+    pub key_timeout_ms: u32,
+    pub keymap: Keymap,
+    pub use_gamepad: bool,
+    pub gamepad_keymap: Keymap,
+    // Keys to hold on given frames, merged with the other input.
+    pub input_script: Option<String>,
+    pub quirks: Quirks,
+    // Random numbers come from this seed, or a random one if not given.
+    pub seed: Option<u32>,
+    pub record_movie: Option<String>,
+    pub play_movie: Option<String>,
+    // Check framebuffer hashes at the movie's checkpoints.
+    pub verify_movie: bool,
+    // Frames to run headless, or 0 to run until the movie ends.
+    pub frames: u64,
+    // Log every instruction run to this file.
+    pub trace_path: Option<String>,
+    pub trace_format: TraceFormat,
+    pub trace_filter: TraceFilter,
+    // Count the instructions run under each call stack into this file.
+    pub profile_path: Option<String>,
+    // How often each address ran, was read and was written, as a
+    // disassembly listing and as an image.
+    pub coverage_path: Option<String>,
+    pub heatmap_path: Option<String>,
+    // Wait for GDB to connect on this port before running.
+    pub gdb_port: Option<u16>,
+    // Serve the Debug Adapter Protocol on "stdio" or a TCP port.
+    pub dap: Option<String>,
+    // Labels and source lines for traces and debugging.
+    pub debug_info_path: Option<String>
+}
+
+impl Default for Options {
+    fn default() -> Options {
+        Options {
+            rom_path: DEFAULT_ROM_PATH.to_string(),
+            frontend: Frontend::Window,
+            palette: Default::default(),
+            display_mode: DisplayMode::Immediate,
+            phosphor_decay: 0.4,
+            border: 0,
+            border_color: None,
+            cycles_per_frame: 1,
+            engine: Engine::Interpreter,
+            terminal_glyphs: Glyphs::HalfBlock,
+            key_timeout_ms: 200,
+            keymap: Default::default(),
+            use_gamepad: true,
+            gamepad_keymap: keymap::default_gamepad(),
+            input_script: None,
+            quirks: Default::default(),
+            seed: None,
+            record_movie: None,
+            play_movie: None,
+            verify_movie: false,
+            frames: 0,
+            trace_path: None,
+            trace_format: TraceFormat::Text,
+            trace_filter: Default::default(),
+            profile_path: None,
+            coverage_path: None,
+            heatmap_path: None,
+            gdb_port: None,
+            dap: None,
+            debug_info_path: None
+        }
+    }
+}
+
+impl ConfigFile {
+    pub fn get(&self, key: &str) -> Option<&ConfigValue> {
+        self.entries.get(key)
+    }
+
+    pub fn get_str(&self, key: &str) -> Result<Option<&str>, String> {
+        match self.entries.get(key) {
+            Some(&ConfigValue::Str(ref s)) => Ok(Some(s)),
+            Some(_) => Err(format!("Config key '{}' should be a string", key)),
+            None => Ok(None)
+        }
+    }
+
+    pub fn get_int(&self, key: &str) -> Result<Option<i64>, String> {
+        match self.entries.get(key) {
+            Some(&ConfigValue::Int(i)) => Ok(Some(i)),
+            Some(_) => Err(format!("Config key '{}' should be an integer", key)),
+            None => Ok(None)
+        }
+    }
+
+    pub fn get_bool(&self, key: &str) -> Result<Option<bool>, String> {
+        match self.entries.get(key) {
+            Some(&ConfigValue::Bool(b)) => Ok(Some(b)),
+            Some(_) => Err(format!("Config key '{}' should be true or false", key)),
+            None => Ok(None)
+        }
+    }
+
+    // Integers are accepted too, so "decay = 1" works as well as "decay = 1.0".
+    pub fn get_float(&self, key: &str) -> Result<Option<f64>, String> {
+        match self.entries.get(key) {
+            Some(&ConfigValue::Float(f)) => Ok(Some(f)),
+            Some(&ConfigValue::Int(i)) => Ok(Some(i as f64)),
+            Some(_) => Err(format!("Config key '{}' should be a number", key)),
+            None => Ok(None)
+        }
+    }
+
+    // Keys directly inside a section, e.g. "keys" gives "1" for "keys.1"
+    // but not "BRIX.1" for "keys.BRIX.1".
+    pub fn section_keys(&self, section: &str) -> Vec<String> {
+        let prefix = format!("{}.", section);
+        let mut keys: Vec<String> = self.entries.keys()
+            .filter(|k| k.starts_with(&prefix) && !k[prefix.len()..].contains('.'))
+            .map(|k| k[prefix.len()..].to_string())
+            .collect();
+        keys.sort();
+        keys
+    }
+}
+
+pub fn parse_config(text: &str) -> Result<ConfigFile, String> {
+    let mut config: ConfigFile = Default::default();
+    let mut section = String::new();
+    for (line_number, raw_line) in text.lines().enumerate() {
+        let line = strip_comment(raw_line).trim();
+        if line.is_empty() {
+            continue;
+        }
+        if line.starts_with('[') {
+            if !line.ends_with(']') {
+                return Err(format!("Line {}: unterminated section header", line_number + 1));
+            }
+            section = parse_key(&line[1..line.len() - 1]);
+            continue;
+        }
+        let equals = match line.find('=') {
+            Some(position) => position,
+            None => return Err(format!("Line {}: expected key = value", line_number + 1))
+        };
+        let key = parse_key(&line[..equals]);
+        let value = match parse_value(line[equals + 1..].trim()) {
+            Ok(value) => value,
+            Err(e) => return Err(format!("Line {}: {}", line_number + 1, e))
+        };
+        let full_key = if section.is_empty() { key } else { format!("{}.{}", section, key) };
+        config.entries.insert(full_key, value);
+    }
+    Ok(config)
+}
+
+pub fn load_config(path: &str) -> Result<ConfigFile, String> {
+    let mut text = String::new();
+    match File::open(path) {
+        Ok(mut f) => {
+            if let Err(e) = f.read_to_string(&mut text) {
+                return Err(format!("Could not read config '{}': {}", path, e));
+            }
+        }
+        Err(e) => return Err(format!("Could not open config '{}': {}", path, e))
+    }
+    parse_config(&text)
+}
+
+fn strip_comment(line: &str) -> &str {
+    let mut in_string = false;
+    for (i, c) in line.char_indices() {
+        if c == '"' {
+            in_string = !in_string;
+        } else if c == '#' && !in_string {
+            return &line[..i];
+        }
+    }
+    line
+}
+
+// Splits the items of an array on commas outside strings.
+fn split_items(text: &str) -> Vec<&str> {
+    let mut items = Vec::new();
+    let mut in_string = false;
+    let mut start = 0;
+    for (i, c) in text.char_indices() {
+        if c == '"' {
+            in_string = !in_string;
+        } else if c == ',' && !in_string {
+            items.push(&text[start..i]);
+            start = i + 1;
+        }
+    }
+    items.push(&text[start..]);
+    items
+}
+
+// Joins the parts of a dotted key, dropping quotes, so that
+// rom."BRIX.ch8" becomes rom.BRIX.ch8.
+fn parse_key(text: &str) -> String {
+    text.trim().split('.').map(|part| part.trim().trim_matches('"')).collect::<Vec<&str>>().join(".")
+}
+
+fn parse_value(text: &str) -> Result<ConfigValue, String> {
+    if text.starts_with('"') {
+        if text.len() < 2 || !text.ends_with('"') {
+            return Err(format!("unterminated string {}", text));
+        }
+        return Ok(ConfigValue::Str(text[1..text.len() - 1].replace("\\\\", "\\")));
+    }
+    if text.starts_with('[') {
+        if !text.ends_with(']') {
+            return Err(format!("unterminated array {}", text));
+        }
+        let inner = text[1..text.len() - 1].trim();
+        let mut items = Vec::new();
+        if !inner.is_empty() {
+            for item in split_items(inner) {
+                let item = item.trim();
+                if !item.is_empty() {
+                    items.push(parse_value(item)?);
+                }
+            }
+        }
+        return Ok(ConfigValue::List(items));
+    }
+    if text == "true" {
+        return Ok(ConfigValue::Bool(true));
+    }
+    if text == "false" {
+        return Ok(ConfigValue::Bool(false));
+    }
+    if let Some(n) = parse_number(text) {
+        return Ok(ConfigValue::Int(n));
+    }
+    match text.parse::<f64>() {
+        Ok(f) => Ok(ConfigValue::Float(f)),
+        Err(_) => Err(format!("invalid value {}", text))
+    }
+}
+
+// Decimal or 0x prefixed hexadecimal, as used for addresses on the command
+// line and in the config file.
+pub fn parse_number(text: &str) -> Option<i64> {
+    let text = text.trim().replace("_", "");
+    if text.starts_with("0x") || text.starts_with("0X") {
+        i64::from_str_radix(&text[2..], 16).ok()
+    } else {
+        text.parse::<i64>().ok()
+    }
+}
+
+pub fn usage() -> String {
+    format!("Usage: rust_chip8 [options] [rom]\n\
+             \x20      rust_chip8 diff-traces [--context <n>] <trace> <trace>\n\
+             \x20      rust_chip8 profile-report <profile>\n\
+             \x20      rust_chip8 analyze [--dot <file>] [--debug-info <file>] <rom>\n\
+             \x20      rust_chip8 decompile [--debug-info <file>] <rom>\n\
+             \x20      rust_chip8 recompile <rom> <output.rs>\n\
+             \x20      rust_chip8 bench [--cycles <n>] [--samples <n>] [rom]\n\
+             \n\
+             Options:\n\
+             \x20 --config <file>           Read settings from <file> (default {})\n\
+             \x20 --frontend <name>         Frontend: window, terminal or headless\n\
+             \x20 --palette <name>          Color palette: {}\n\
+             \x20 --fg <#RRGGBB>            Foreground color\n\
+             \x20 --bg <#RRGGBB>            Background color\n\
+             \x20 --display-mode <m>        Display mode: immediate, vblank or phosphor\n\
+             \x20 --decay <0.0-1.0>         Brightness lost per frame in phosphor mode (default 0.4)\n\
+             \x20 --border <px>             Least space around the screen in the window (default 0)\n\
+             \x20 --border-color <#RRGGBB>  Border color (default the background)\n\
+             \x20 --cycles-per-frame <n>    Instructions run per 60 Hz frame (default 1)\n\
+             \x20 --engine <name>           CPU engine: interpreter, predecoded or cached\n\
+             \x20 --glyphs <name>           Terminal glyphs: halfblock or braille\n\
+             \x20 --key-timeout <ms>        Terminal key release delay (default 200)\n\
+             \x20 --layout <name>           Keyboard layout: qwerty, azerty or keypad\n\
+             \x20 --no-gamepad              Ignore gamepads\n\
+             \x20 --input-script <file>     Hold keys on the frames given in <file>\n\
+             \x20 --quirk <name>            Enable a quirk: {}\n\
+             \x20 --seed <n>                Random number seed\n\
+             \x20 --record <file>           Record the keys pressed to a movie file\n\
+             \x20 --play <file>             Play back a movie file\n\
+             \x20 --verify                  Check the framebuffer at the movie's checkpoints\n\
+             \x20 --frames <n>              Frames to run with the headless frontend\n\
+             \x20 --trace <file>            Log every instruction run to <file>\n\
+             \x20 --trace-format <f>        Trace format: text or binary\n\
+             \x20 --trace-pc <from-to>      Only trace instructions in this address range\n\
+             \x20 --trace-class <list>      Only trace these kinds of instruction\n\
+             \x20 --profile <file>          Count instructions run per call stack in <file>\n\
+             \x20 --coverage <file>         Write a disassembly with run, read and write counts\n\
+             \x20 --heatmap <file>          Draw those counts over memory as a PPM image\n\
+             \x20 --gdb <port>              Wait for GDB on <port>\n\
+             \x20 --dap <stdio|port>        Serve the Debug Adapter Protocol\n\
+             \x20 --debug-info <file>       Read labels and source lines from <file>\n\
+             \x20 --help                    Show this message",
+            DEFAULT_CONFIG_PATH, palette::builtin_palette_names().join(", "), quirks::quirk_names().join(", "))
+}
+
+// Settings come from the defaults, then the config file, then the command
+// line, each overriding the last.
+pub fn load_options(args: &[String]) -> Result<Options, String> {
+    let mut options: Options = Default::default();
+    let mut flags: Vec<(String, String)> = Vec::new();
+    let mut rom_path = None;
+
+    let mut i = 0;
+    while i < args.len() {
+        let arg = &args[i];
+        if arg == "--help" || arg == "-h" {
+            return Err(usage());
+        } else if arg.starts_with("--") && SWITCHES.contains(&&arg[2..]) {
+            flags.push((arg[2..].to_string(), "true".to_string()));
+        } else if arg.starts_with("--") {
+            if i + 1 >= args.len() {
+                return Err(format!("Missing value for {}", arg));
+            }
+            flags.push((arg[2..].to_string(), args[i + 1].clone()));
+            i += 1;
+        } else if rom_path.is_none() {
+            rom_path = Some(arg.clone());
+        } else {
+            return Err(format!("Unexpected argument '{}'\n{}", arg, usage()));
+        }
+        i += 1;
+    }
+
+    let config_path = flags.iter().find(|&&(ref name, _)| name == "config").map(|&(_, ref value)| value.clone());
+    let config = match config_path {
+        Some(ref path) => load_config(path)?,
+        None => {
+            if ::std::path::Path::new(DEFAULT_CONFIG_PATH).exists() {
+                load_config(DEFAULT_CONFIG_PATH)?
+            } else {
+                Default::default()
+            }
+        }
+    };
+    apply_config(&mut options, &config)?;
+
+    // A palette replaces every color, so apply it before --fg and --bg.
+    for &(ref name, ref value) in flags.iter().filter(|&&(ref name, _)| name == "palette") {
+        apply_flag(&mut options, name, value)?;
+    }
+    for &(ref name, ref value) in flags.iter().filter(|&&(ref name, _)| name != "palette") {
+        apply_flag(&mut options, name, value)?;
+    }
+    if let Some(path) = rom_path {
+        options.rom_path = path;
+    }
+    if options.record_movie.is_some() && options.play_movie.is_some() {
+        return Err("Can't record and play a movie at the same time".to_string());
+    }
+    // The terminal frontend draws to stdout and reads keys from stdin.
+    if options.frontend == Frontend::Terminal && options.dap.as_ref().is_some_and(|dap| dap == "stdio") {
+        return Err("The terminal frontend can't serve DAP on stdio; use a port".to_string());
+    }
+
+    // Key bindings can be overridden per ROM, so wait until the ROM is known.
+    let rom_name = match ::std::path::Path::new(&options.rom_path).file_name() {
+        Some(name) => name.to_string_lossy().into_owned(),
+        None => String::new()
+    };
+    let layout = flags.iter().rev().find(|&&(ref name, _)| name == "layout").map(|&(_, ref value)| &value[..]);
+    // A terminal only sends characters, never keypad keys.
+    if options.frontend == Frontend::Terminal && keymap::layout_name(&config, &rom_name, layout)? == "keypad" {
+        return Err("The keypad layout can't be used with the terminal frontend".to_string());
+    }
+    options.keymap = keymap::from_config(&config, &rom_name, layout)?;
+    Ok(options)
+}
+
+pub fn apply_config(options: &mut Options, config: &ConfigFile) -> Result<(), String> {
+    if let Some(path) = config.get_str("rom")? {
+        options.rom_path = path.to_string();
+    }
+    if let Some(frontend) = config.get_str("frontend")? {
+        apply_flag(options, "frontend", frontend)?;
+    }
+    if let Some(name) = config.get_str("display.palette")? {
+        apply_flag(options, "palette", name)?;
+    }
+    match config.get("display.colors") {
+        Some(&ConfigValue::List(ref colors)) => {
+            if colors.len() > 4 {
+                return Err("display.colors takes at most 4 colors".to_string());
+            }
+            for (i, color) in colors.iter().enumerate() {
+                match *color {
+                    ConfigValue::Str(ref text) => options.palette.colors[i] = palette::parse_color(text)?,
+                    _ => return Err("display.colors should be a list of \"#RRGGBB\" strings".to_string())
+                }
+            }
+        }
+        Some(_) => return Err("display.colors should be a list of \"#RRGGBB\" strings".to_string()),
+        None => ()
+    }
+    if let Some(color) = config.get_str("display.background")? {
+        options.palette.colors[0] = palette::parse_color(color)?;
+    }
+    if let Some(color) = config.get_str("display.foreground")? {
+        options.palette.colors[1] = palette::parse_color(color)?;
+    }
+    if let Some(mode) = config.get_str("display.mode")? {
+        options.display_mode = render::parse_display_mode(mode)?;
+    }
+    if let Some(decay) = config.get_float("display.decay")? {
+        options.phosphor_decay = check_decay(decay)?;
+    }
+    if let Some(border) = config.get_int("display.border")? {
+        if border < 0 || border > i64::from(u32::MAX) {
+            return Err("display.border should be a number of pixels".to_string());
+        }
+        options.border = border as u32;
+    }
+    if let Some(color) = config.get_str("display.border_color")? {
+        options.border_color = Some(palette::parse_color(color)?);
+    }
+    if let Some(cycles) = config.get_int("cpu.cycles_per_frame")? {
+        if cycles < 1 || cycles > i64::from(u32::MAX) {
+            return Err(format!("cpu.cycles_per_frame should be between 1 and {}", u32::MAX));
+        }
+        options.cycles_per_frame = cycles as u32;
+    }
+    if let Some(name) = config.get_str("cpu.engine")? {
+        options.engine = engine::parse_engine(name)?;
+    }
+    if let Some(enabled) = config.get_bool("gamepad.enabled")? {
+        options.use_gamepad = enabled;
+    }
+    options.gamepad_keymap = keymap::gamepad_from_config(config)?;
+    for name in config.section_keys("quirks") {
+        if let Some(value) = config.get_bool(&format!("quirks.{}", name))? {
+            quirks::set_quirk(&mut options.quirks, &name, value)?;
+        }
+    }
+    if let Some(path) = config.get_str("trace.file")? {
+        options.trace_path = Some(path.to_string());
+    }
+    if let Some(format) = config.get_str("trace.format")? {
+        options.trace_format = trace::parse_format(format)?;
+    }
+    if let Some(range) = config.get_str("trace.pc")? {
+        options.trace_filter.pc_range = Some(trace::parse_pc_range(range)?);
+    }
+    match config.get("trace.classes") {
+        Some(&ConfigValue::List(ref names)) => {
+            let mut classes = Vec::new();
+            for name in names {
+                match *name {
+                    ConfigValue::Str(ref name) => classes.append(&mut trace::parse_classes(name)?),
+                    _ => return Err("trace.classes should be a list of instruction classes".to_string())
+                }
+            }
+            options.trace_filter.classes = classes;
+        }
+        Some(&ConfigValue::Str(ref names)) => options.trace_filter.classes = trace::parse_classes(names)?,
+        Some(_) => return Err("trace.classes should be a list of instruction classes".to_string()),
+        None => ()
+    }
+    if let Some(path) = config.get_str("profile.file")? {
+        options.profile_path = Some(path.to_string());
+    }
+    if let Some(path) = config.get_str("coverage.listing")? {
+        options.coverage_path = Some(path.to_string());
+    }
+    if let Some(path) = config.get_str("coverage.heatmap")? {
+        options.heatmap_path = Some(path.to_string());
+    }
+    if let Some(port) = config.get_int("debug.gdb_port")? {
+        if port < 1 || port > 65535 {
+            return Err("debug.gdb_port should be between 1 and 65535".to_string());
+        }
+        options.gdb_port = Some(port as u16);
+    }
+    if let Some(transport) = config.get_str("debug.dap")? {
+        options.dap = Some(transport.to_string());
+    }
+    if let Some(path) = config.get_str("debug.info")? {
+        options.debug_info_path = Some(path.to_string());
+    }
+    if let Some(glyphs) = config.get_str("terminal.glyphs")? {
+        options.terminal_glyphs = tui::parse_glyphs(glyphs)?;
+    }
+    if let Some(timeout) = config.get_int("terminal.key_timeout")? {
+        if timeout < 1 || timeout > i64::from(u32::MAX) {
+            return Err(format!("terminal.key_timeout should be between 1 and {}", u32::MAX));
+        }
+        options.key_timeout_ms = timeout as u32;
+    }
+    Ok(())
+}
+
+fn apply_flag(options: &mut Options, name: &str, value: &str) -> Result<(), String> {
+    match name {
+        "config" | "layout" => (),
+        "frontend" => {
+            options.frontend = match value {
+                "window" => Frontend::Window,
+                "terminal" => Frontend::Terminal,
+                "headless" => Frontend::Headless,
+                _ => return Err(format!("Unknown frontend '{}', expected window, terminal or headless", value))
+            }
+        }
+        "palette" => {
+            match palette::builtin_palette(value) {
+                Some(p) => options.palette = p,
+                None => return Err(format!("Unknown palette '{}', expected one of: {}",
+                                           value, palette::builtin_palette_names().join(", ")))
+            }
+        }
+        "fg" => options.palette.colors[1] = palette::parse_color(value)?,
+        "bg" => options.palette.colors[0] = palette::parse_color(value)?,
+        "display-mode" => options.display_mode = render::parse_display_mode(value)?,
+        "decay" => {
+            match value.parse::<f64>() {
+                Ok(decay) => options.phosphor_decay = check_decay(decay)?,
+                Err(_) => return Err(format!("Invalid decay '{}'", value))
+            }
+        }
+        "border" => {
+            match value.parse::<u32>() {
+                Ok(border) => options.border = border,
+                Err(_) => return Err(format!("Invalid border '{}'", value))
+            }
+        }
+        "border-color" => options.border_color = Some(palette::parse_color(value)?),
+        "cycles-per-frame" => {
+            match value.parse::<u32>() {
+                Ok(cycles) if cycles > 0 => options.cycles_per_frame = cycles,
+                _ => return Err(format!("Invalid cycles per frame '{}'", value))
+            }
+        }
+        "engine" => options.engine = engine::parse_engine(value)?,
+        "no-gamepad" => options.use_gamepad = false,
+        "input-script" => options.input_script = Some(value.to_string()),
+        "quirk" => quirks::set_quirk(&mut options.quirks, value, true)?,
+        "seed" => {
+            match parse_number(value) {
+                Some(seed) if seed >= 0 && seed <= i64::from(u32::MAX) => options.seed = Some(seed as u32),
+                _ => return Err(format!("Invalid seed '{}'", value))
+            }
+        }
+        "record" => options.record_movie = Some(value.to_string()),
+        "play" => options.play_movie = Some(value.to_string()),
+        "verify" => options.verify_movie = true,
+        "frames" => {
+            match value.parse::<u64>() {
+                Ok(frames) => options.frames = frames,
+                Err(_) => return Err(format!("Invalid frame count '{}'", value))
+            }
+        }
+        "trace" => options.trace_path = Some(value.to_string()),
+        "trace-format" => options.trace_format = trace::parse_format(value)?,
+        "trace-pc" => options.trace_filter.pc_range = Some(trace::parse_pc_range(value)?),
+        "trace-class" => options.trace_filter.classes = trace::parse_classes(value)?,
+        "profile" => options.profile_path = Some(value.to_string()),
+        "coverage" => options.coverage_path = Some(value.to_string()),
+        "heatmap" => options.heatmap_path = Some(value.to_string()),
+        "gdb" => {
+            match value.parse::<u16>() {
+                Ok(port) if port > 0 => options.gdb_port = Some(port),
+                _ => return Err(format!("Invalid GDB port '{}'", value))
+            }
+        }
+        "dap" => options.dap = Some(value.to_string()),
+        "debug-info" => options.debug_info_path = Some(value.to_string()),
+        "glyphs" => options.terminal_glyphs = tui::parse_glyphs(value)?,
+        "key-timeout" => {
+            match value.parse::<u32>() {
+                Ok(timeout) if timeout > 0 => options.key_timeout_ms = timeout,
+                _ => return Err(format!("Invalid key timeout '{}'", value))
+            }
+        }
+        _ => return Err(format!("Unknown option --{}\n{}", name, usage()))
+    }
+    Ok(())
+}
+
+fn check_decay(decay: f64) -> Result<f32, String> {
+    if !(0.0..=1.0).contains(&decay) {
+        return Err(format!("Decay {} should be between 0.0 and 1.0", decay));
+    }
+    Ok(decay as f32)
+}
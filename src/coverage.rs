@@ -0,0 +1,204 @@
+use std::fs::File;
+use std::io::prelude::*;
+use std::io::BufWriter;
+
+use {Chip8State, get_opcode};
+use debuginfo::DebugInfo;
+use disasm::{self, Instruction};
+use monitor::Monitor;
+
+const MEMORY_SIZE: usize = 4096;
+
+// The heatmap shows memory as a square of 64 by 64 bytes, each drawn as a
+// block of this many pixels.
+const HEATMAP_SCALE: usize = 8;
+
+// Rewrite the reports this often too, in case the process is killed.
+const WRITE_EVERY_FRAMES: u32 = 60;
+
+// How often each address was run as an instruction, and each byte read
+// or written by one.
+pub struct Coverage {
+    listing_path: Option<String>,
+    heatmap_path: Option<String>,
+    debug_info: DebugInfo,
+    // The ROM as loaded, kept up to date with the program's own writes so
+    // the listing shows code the program changed.
+    rom: Vec<u8>,
+    runs: Vec<u64>,
+    reads: Vec<u64>,
+    writes: Vec<u64>,
+    instruction: Instruction,
+    index: usize,
+    frames_since_write: u32
+}
+
+impl Coverage {
+    pub fn create(listing_path: Option<String>, heatmap_path: Option<String>, rom: &[u8],
+                  debug_info: DebugInfo) -> Result<Coverage, String> {
+        for path in listing_path.iter().chain(heatmap_path.iter()) {
+            if let Err(e) = File::create(path) {
+                return Err(format!("Could not create '{}': {}", path, e));
+            }
+        }
+        Ok(Coverage {
+            listing_path,
+            heatmap_path,
+            debug_info,
+            rom: rom.to_vec(),
+            runs: vec![0; MEMORY_SIZE],
+            reads: vec![0; MEMORY_SIZE],
+            writes: vec![0; MEMORY_SIZE],
+            instruction: Instruction::Unknown(0),
+            index: 0,
+            frames_since_write: 0
+        })
+    }
+
+    fn is_code(&self, address: usize) -> bool {
+        self.runs[address] > 0 || (address > 0 && self.runs[address - 1] > 0)
+    }
+
+    // The ROM disassembled with how often each instruction ran and each
+    // byte was read and written. Bytes that only ever ran as instructions
+    // are listed as code, bytes only read or written as data, and the rest
+    // as instructions with no runs, which may be code the run never reached
+    // or data it never used.
+    pub fn listing(&self) -> String {
+        let end = 0x200 + self.rom.len();
+        let code = (0x200..end).filter(|&address| self.is_code(address)).count();
+        let data = (0x200..end).filter(|&address| !self.is_code(address) &&
+                                       (self.reads[address] > 0 || self.writes[address] > 0)).count();
+        let percent = |count: usize| if self.rom.is_empty() { 0.0 } else { count as f64 * 100.0 / self.rom.len() as f64 };
+        let mut listing = format!("; {} bytes: {} run as code ({:.1}%), {} used as data ({:.1}%), {} untouched\n",
+                                  self.rom.len(), code, percent(code), data, percent(data),
+                                  self.rom.len() - code - data);
+        listing.push_str(&format!("; {:<5} {:<5} {:>10} {:>8} {:>8}\n", "addr", "bytes", "runs", "reads", "writes"));
+        let mut address = 0x200;
+        while address < end {
+            if let Some(label) = self.debug_info.label_at(address) {
+                listing.push_str(&format!("{}:\n", label));
+            }
+            let byte = self.rom[address - 0x200];
+            let runs = self.runs[address];
+            let used_as_data = self.reads[address] > 0 || self.writes[address] > 0;
+            // Show a lone byte as data when it's used as data, is the last
+            // one, or code starts in the middle of the pair.
+            let single = (runs == 0 && used_as_data) || address + 1 >= end ||
+                (runs == 0 && self.runs[address + 1] > 0);
+            let counts = |runs: &str, address: usize, length: usize| {
+                let reads: u64 = self.reads[address..address + length].iter().sum();
+                let writes: u64 = self.writes[address..address + length].iter().sum();
+                format!("{:>10} {:>8} {:>8}", runs,
+                        if reads > 0 { reads.to_string() } else { String::new() },
+                        if writes > 0 { writes.to_string() } else { String::new() })
+            };
+            if single {
+                listing.push_str(&format!("  {:03X}   {:02X}    {}  DB #{:02X}\n",
+                                          address, byte, counts("", address, 1), byte));
+                address += 1;
+                continue;
+            }
+            let opcode = get_opcode(address - 0x200, &self.rom);
+            let runs = if runs > 0 { runs.to_string() } else { "-".to_string() };
+            listing.push_str(&format!("  {:03X}   {:04X}  {}  {}\n", address, opcode, counts(&runs, address, 2),
+                                      disasm::disassemble_with_labels(opcode, &self.debug_info)));
+            address += 2;
+        }
+        listing
+    }
+
+    // A binary PPM of the 4 KiB address space, 64 bytes to a row, with how
+    // often each byte ran as code in red, was read in green and written in
+    // blue. Counts are on a log scale, so rarely used bytes still show.
+    pub fn heatmap(&self) -> Vec<u8> {
+        let side = 64 * HEATMAP_SCALE;
+        let scale = |counts: &[u64]| {
+            let max = counts.iter().cloned().max().unwrap_or(0);
+            let max = ((max + 1) as f64).ln();
+            counts.iter().map(|&count| {
+                if count == 0 { 0 } else { (64.0 + 191.0 * ((count + 1) as f64).ln() / max) as u8 }
+            }).collect::<Vec<u8>>()
+        };
+        // Both bytes of an instruction ran.
+        let code: Vec<u64> = (0..MEMORY_SIZE).map(|address| {
+            if address > 0 { self.runs[address] + self.runs[address - 1] } else { self.runs[address] }
+        }).collect();
+        let red = scale(&code);
+        let green = scale(&self.reads);
+        let blue = scale(&self.writes);
+        let mut image = format!("P6\n{} {}\n255\n", side, side).into_bytes();
+        for y in 0..side {
+            for x in 0..side {
+                let address = (y / HEATMAP_SCALE) * 64 + x / HEATMAP_SCALE;
+                image.extend_from_slice(&[red[address], green[address], blue[address]]);
+            }
+        }
+        image
+    }
+
+    fn write(&self) -> Result<(), String> {
+        if let Some(ref path) = self.listing_path {
+            write_file(path, self.listing().as_bytes())?;
+        }
+        if let Some(ref path) = self.heatmap_path {
+            write_file(path, &self.heatmap())?;
+        }
+        Ok(())
+    }
+}
+
+fn write_file(path: &str, data: &[u8]) -> Result<(), String> {
+    let error = |e: ::std::io::Error| format!("Could not write '{}': {}", path, e);
+    let mut out = BufWriter::new(File::create(path).map_err(error)?);
+    out.write_all(data).map_err(error)?;
+    out.flush().map_err(error)
+}
+
+impl Monitor for Coverage {
+    fn before(&mut self, state: &mut Chip8State, memory: &mut Vec<u8>) {
+        // The interpreter will fail on a pc past the end of memory, so
+        // leave that for it to report.
+        if state.pc + 1 >= memory.len() {
+            self.instruction = Instruction::Unknown(0);
+            return;
+        }
+        self.runs[state.pc] += 1;
+        self.instruction = disasm::decode(get_opcode(state.pc, memory));
+        self.index = state.index as usize;
+    }
+
+    fn after(&mut self, _state: &Chip8State, memory: &[u8]) {
+        for address in self.instruction.memory_reads(self.index) {
+            if address < MEMORY_SIZE {
+                self.reads[address] += 1;
+            }
+        }
+        for address in self.instruction.memory_writes(self.index) {
+            if address < MEMORY_SIZE {
+                self.writes[address] += 1;
+                if address >= 0x200 && address - 0x200 < self.rom.len() {
+                    self.rom[address - 0x200] = memory[address];
+                }
+            }
+        }
+    }
+
+    fn end_frame(&mut self) {
+        self.frames_since_write += 1;
+        if self.frames_since_write >= WRITE_EVERY_FRAMES {
+            self.frames_since_write = 0;
+            if let Err(e) = self.write() {
+                eprintln!("{}", e);
+            }
+        }
+    }
+}
+
+impl Drop for Coverage {
+    fn drop(&mut self) {
+        if let Err(e) = self.write() {
+            eprintln!("{}", e);
+        }
+    }
+}
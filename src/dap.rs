@@ -0,0 +1,593 @@
+use std::io;
+use std::io::prelude::*;
+use std::io::BufReader;
+use std::net::TcpListener;
+use std::sync::mpsc;
+use std::thread;
+
+use config::parse_number;
+use debuginfo::DebugInfo;
+use disasm;
+use disasm::Instruction;
+use json::{object, parse_json, Json};
+use monitor::Monitor;
+use {Chip8State, get_opcode};
+
+// The CHIP-8 has one thread of execution.
+const THREAD_ID: i64 = 1;
+
+const REGISTERS_REFERENCE: i64 = 1;
+const STACK_REFERENCE: i64 = 2;
+
+// A disassembly view never needs more than one entry per address.
+const MAX_INSTRUCTIONS: i64 = 0x1000;
+
+const BASE64: &[u8; 64] = b"ABCDEFGHIJKLMNOPQRSTUVWXYZabcdefghijklmnopqrstuvwxyz0123456789+/";
+
+pub fn base64_encode(data: &[u8]) -> String {
+    let mut text = String::new();
+    for chunk in data.chunks(3) {
+        let bits = chunk.iter().enumerate().fold(0u32, |bits, (i, &byte)| bits | u32::from(byte) << (16 - 8 * i));
+        for i in 0..4 {
+            if i <= chunk.len() {
+                text.push(BASE64[(bits >> (18 - 6 * i) & 0x3F) as usize] as char);
+            } else {
+                text.push('=');
+            }
+        }
+    }
+    text
+}
+
+pub fn base64_decode(text: &str) -> Option<Vec<u8>> {
+    let mut data = Vec::new();
+    let mut bits = 0u32;
+    let mut count = 0;
+    for c in text.bytes().filter(|&c| c != b'=' && !c.is_ascii_whitespace()) {
+        let value = BASE64.iter().position(|&b| b == c)? as u32;
+        bits = bits << 6 | value;
+        count += 6;
+        if count >= 8 {
+            count -= 8;
+            data.push((bits >> count) as u8);
+        }
+    }
+    Some(data)
+}
+
+// Reads "Content-Length: n" framed messages until the client goes away.
+fn read_messages<R: BufRead>(mut reader: R, sender: mpsc::Sender<Json>) {
+    loop {
+        let mut length = None;
+        loop {
+            let mut header = String::new();
+            match reader.read_line(&mut header) {
+                Ok(0) | Err(_) => return,
+                Ok(_) => ()
+            }
+            let header = header.trim();
+            if header.is_empty() {
+                break;
+            }
+            if header.to_lowercase().starts_with("content-length:") {
+                length = header["content-length:".len()..].trim().parse::<usize>().ok();
+            }
+        }
+        let mut body = vec![0u8; match length { Some(length) => length, None => continue }];
+        if reader.read_exact(&mut body).is_err() {
+            return;
+        }
+        if let Ok(message) = parse_json(&String::from_utf8_lossy(&body)) {
+            if sender.send(message).is_err() {
+                return;
+            }
+        }
+    }
+}
+
+// How to run after a resuming request.
+#[derive(Debug, Clone, Copy, PartialEq)]
+enum Resume {
+    Run,
+    StepIn,
+    // Until the instruction after a call, at the same stack depth.
+    StepOver(usize, usize),
+    // Until the stack is shallower than this.
+    StepOut(usize)
+}
+
+// A Debug Adapter Protocol server, run as a monitor like the GDB stub.
+// Execution waits for the client's configurationDone before starting.
+pub struct DapServer {
+    receiver: mpsc::Receiver<Json>,
+    out: Box<dyn Write + Send>,
+    seq: i64,
+    debug_info: DebugInfo,
+    instruction_breakpoints: Vec<usize>,
+    // Breakpoints by label.
+    function_breakpoints: Vec<usize>,
+    // (source path, addresses)
+    source_breakpoints: Vec<(String, Vec<usize>)>,
+    resume: Resume,
+    // Set until the first instruction after resuming has started.
+    resuming: bool,
+    pause_requested: bool,
+    configured: bool,
+    stop_on_entry: bool
+}
+
+impl DapServer {
+    // Serves on stdin and stdout for "stdio", otherwise waits for a client
+    // on the given TCP port.
+    pub fn start(transport: &str, debug_info: DebugInfo) -> Result<DapServer, String> {
+        let (sender, receiver) = mpsc::channel();
+        let out: Box<dyn Write + Send> = if transport == "stdio" {
+            thread::spawn(move || {
+                let stdin = io::stdin();
+                read_messages(stdin.lock(), sender);
+            });
+            Box::new(io::stdout())
+        } else {
+            let port = match transport.parse::<u16>() {
+                Ok(port) => port,
+                Err(_) => return Err(format!("Invalid debug adapter transport '{}', expected stdio or a port", transport))
+            };
+            let listener = match TcpListener::bind(("127.0.0.1", port)) {
+                Ok(listener) => listener,
+                Err(e) => return Err(format!("Could not listen for a debug adapter client on port {}: {}", port, e))
+            };
+            eprintln!("Waiting for a debug adapter client on port {}", port);
+            let stream = match listener.accept() {
+                Ok((stream, _)) => stream,
+                Err(e) => return Err(format!("Could not accept debug adapter connection: {}", e))
+            };
+            let reader = match stream.try_clone() {
+                Ok(reader) => reader,
+                Err(e) => return Err(format!("Could not accept debug adapter connection: {}", e))
+            };
+            thread::spawn(move || read_messages(BufReader::new(reader), sender));
+            Box::new(stream)
+        };
+        Ok(DapServer {
+            receiver,
+            out,
+            seq: 1,
+            debug_info,
+            instruction_breakpoints: Vec::new(),
+            function_breakpoints: Vec::new(),
+            source_breakpoints: Vec::new(),
+            resume: Resume::Run,
+            resuming: false,
+            pause_requested: false,
+            configured: false,
+            stop_on_entry: false
+        })
+    }
+
+    fn send(&mut self, mut members: Vec<(&str, Json)>) {
+        members.insert(0, ("seq", Json::from(self.seq)));
+        self.seq += 1;
+        let body = object(members).to_string();
+        let _ = write!(self.out, "Content-Length: {}\r\n\r\n{}", body.len(), body);
+        let _ = self.out.flush();
+    }
+
+    fn send_event(&mut self, event: &str, body: Json) {
+        self.send(vec![("type", Json::from("event")), ("event", Json::from(event)), ("body", body)]);
+    }
+
+    fn respond(&mut self, request: &Json, result: Result<Json, String>) {
+        let mut members = vec![
+            ("type", Json::from("response")),
+            ("request_seq", request.get("seq").clone()),
+            ("command", request.get("command").clone())
+        ];
+        match result {
+            Ok(body) => {
+                members.push(("success", Json::from(true)));
+                members.push(("body", body));
+            }
+            Err(message) => {
+                members.push(("success", Json::from(false)));
+                members.push(("message", Json::from(message)));
+            }
+        }
+        self.send(members);
+    }
+
+    fn is_breakpoint(&self, pc: usize) -> bool {
+        self.instruction_breakpoints.contains(&pc) || self.function_breakpoints.contains(&pc) ||
+            self.source_breakpoints.iter().any(|&(_, ref addresses)| addresses.contains(&pc))
+    }
+
+    fn next_message(&mut self) -> Json {
+        match self.receiver.recv() {
+            Ok(message) => message,
+            // The client has gone, and the program was only running for it.
+            Err(_) => ::std::process::exit(0)
+        }
+    }
+
+    fn stopped(&mut self, reason: &str, state: &mut Chip8State, memory: &mut Vec<u8>) {
+        self.send_event("stopped", object(vec![
+            ("reason", Json::from(reason)),
+            ("threadId", Json::from(THREAD_ID)),
+            ("allThreadsStopped", Json::from(true))
+        ]));
+        loop {
+            let message = self.next_message();
+            if let Some(resume) = self.handle(&message, state, memory) {
+                self.resume = resume;
+                self.resuming = true;
+                self.pause_requested = false;
+                return;
+            }
+        }
+    }
+
+    // Handles a request, returning how to resume if it resumes execution.
+    fn handle(&mut self, request: &Json, state: &mut Chip8State, memory: &mut Vec<u8>) -> Option<Resume> {
+        if request.get("type").as_str() != Some("request") {
+            return None;
+        }
+        let command = request.get("command").as_str().unwrap_or("").to_string();
+        let args = request.get("arguments");
+        let mut resume = None;
+        let result = match &command[..] {
+            "initialize" => Ok(object(vec![
+                ("supportsConfigurationDoneRequest", Json::from(true)),
+                ("supportsReadMemoryRequest", Json::from(true)),
+                ("supportsWriteMemoryRequest", Json::from(true)),
+                ("supportsDisassembleRequest", Json::from(true)),
+                ("supportsInstructionBreakpoints", Json::from(true)),
+                ("supportsFunctionBreakpoints", Json::from(true)),
+                ("supportsSetVariable", Json::from(true)),
+                ("supportsTerminateRequest", Json::from(true))
+            ])),
+            "launch" | "attach" => {
+                self.stop_on_entry = args.get("stopOnEntry").as_bool().unwrap_or(false);
+                Ok(Json::Null)
+            }
+            "configurationDone" => {
+                self.configured = true;
+                Ok(Json::Null)
+            }
+            "setBreakpoints" => Ok(self.set_breakpoints(args)),
+            "setInstructionBreakpoints" => Ok(self.set_instruction_breakpoints(args)),
+            "setFunctionBreakpoints" => Ok(self.set_function_breakpoints(args)),
+            "threads" => Ok(object(vec![("threads", Json::from(vec![
+                object(vec![("id", Json::from(THREAD_ID)), ("name", Json::from("CHIP-8"))])
+            ]))])),
+            "stackTrace" => Ok(self.stack_trace(args, state, memory)),
+            "scopes" => Ok(object(vec![("scopes", Json::from(vec![
+                object(vec![("name", Json::from("Registers")), ("variablesReference", Json::from(REGISTERS_REFERENCE)),
+                            ("expensive", Json::from(false))]),
+                object(vec![("name", Json::from("Stack")), ("variablesReference", Json::from(STACK_REFERENCE)),
+                            ("expensive", Json::from(false))])
+            ]))])),
+            "variables" => Ok(variables(args, state)),
+            "setVariable" => set_variable(args, state),
+            "readMemory" => read_memory(args, memory),
+            "writeMemory" => write_memory(args, memory),
+            "disassemble" => Ok(disassemble(args, memory, &self.debug_info)),
+            "continue" => {
+                resume = Some(Resume::Run);
+                Ok(object(vec![("allThreadsContinued", Json::from(true))]))
+            }
+            "next" => {
+                resume = Some(match disasm::decode(get_opcode(state.pc, memory)) {
+                    Instruction::Call(_) => Resume::StepOver(state.pc + 2, state.stack_pointer),
+                    _ => Resume::StepIn
+                });
+                Ok(Json::Null)
+            }
+            "stepIn" => {
+                resume = Some(Resume::StepIn);
+                Ok(Json::Null)
+            }
+            "stepOut" => {
+                resume = Some(if state.stack_pointer > 0 { Resume::StepOut(state.stack_pointer) } else { Resume::Run });
+                Ok(Json::Null)
+            }
+            "pause" => {
+                self.pause_requested = true;
+                Ok(Json::Null)
+            }
+            "disconnect" | "terminate" => {
+                self.respond(request, Ok(Json::Null));
+                self.send_event("terminated", Json::Null);
+                ::std::process::exit(0);
+            }
+            _ => Err(format!("Unsupported request '{}'", command))
+        };
+        self.respond(request, result);
+        if command == "initialize" {
+            self.send_event("initialized", Json::Null);
+        }
+        resume
+    }
+
+    fn set_breakpoints(&mut self, args: &Json) -> Json {
+        let path = args.get("source").get("path").as_str().unwrap_or("").to_string();
+        let mut addresses = Vec::new();
+        let mut breakpoints = Vec::new();
+        for requested in args.get("breakpoints").as_array() {
+            let line = requested.get("line").as_i64().unwrap_or(0);
+            match self.debug_info.address_for_line(&path, line as u32) {
+                Some((address, actual_line)) => {
+                    addresses.push(address);
+                    breakpoints.push(object(vec![
+                        ("verified", Json::from(true)),
+                        ("line", Json::from(i64::from(actual_line))),
+                        ("instructionReference", Json::from(format!("0x{:03X}", address)))
+                    ]));
+                }
+                None => {
+                    breakpoints.push(object(vec![
+                        ("verified", Json::from(false)),
+                        ("message", Json::from("No code at this line in the debug info"))
+                    ]));
+                }
+            }
+        }
+        self.source_breakpoints.retain(|&(ref source, _)| *source != path);
+        self.source_breakpoints.push((path, addresses));
+        object(vec![("breakpoints", Json::from(breakpoints))])
+    }
+
+    fn set_instruction_breakpoints(&mut self, args: &Json) -> Json {
+        self.instruction_breakpoints.clear();
+        let mut breakpoints = Vec::new();
+        for requested in args.get("breakpoints").as_array() {
+            let reference = requested.get("instructionReference").as_str().and_then(parse_number);
+            let offset = requested.get("offset").as_i64().unwrap_or(0);
+            match reference {
+                Some(reference) if reference + offset >= 0 && reference + offset < 0x1000 => {
+                    self.instruction_breakpoints.push((reference + offset) as usize);
+                    breakpoints.push(object(vec![("verified", Json::from(true))]));
+                }
+                _ => breakpoints.push(object(vec![("verified", Json::from(false))]))
+            }
+        }
+        object(vec![("breakpoints", Json::from(breakpoints))])
+    }
+
+    // Functions are labels, or label+offset.
+    fn set_function_breakpoints(&mut self, args: &Json) -> Json {
+        self.function_breakpoints.clear();
+        let mut breakpoints = Vec::new();
+        for requested in args.get("breakpoints").as_array() {
+            let name = requested.get("name").as_str().unwrap_or("");
+            match self.debug_info.resolve(name) {
+                Some(address) => {
+                    self.function_breakpoints.push(address);
+                    let mut breakpoint = vec![
+                        ("verified", Json::from(true)),
+                        ("instructionReference", Json::from(format!("0x{:03X}", address)))
+                    ];
+                    if let Some((path, line)) = self.debug_info.line_for_address(address) {
+                        breakpoint.push(("source", object(vec![("path", Json::from(path))])));
+                        breakpoint.push(("line", Json::from(i64::from(line))));
+                    }
+                    breakpoints.push(object(breakpoint));
+                }
+                None => breakpoints.push(object(vec![
+                    ("verified", Json::from(false)),
+                    ("message", Json::from(format!("No label '{}' in the debug info", name)))
+                ]))
+            }
+        }
+        object(vec![("breakpoints", Json::from(breakpoints))])
+    }
+
+    // The current instruction, then the call that led to each subroutine
+    // on the stack, innermost first.
+    fn stack_trace(&self, args: &Json, state: &Chip8State, memory: &[u8]) -> Json {
+        let mut addresses = vec![state.pc];
+        for depth in (0..state.stack_pointer).rev() {
+            addresses.push(state.stack[depth]);
+        }
+        let mut frames = Vec::new();
+        for (id, &address) in addresses.iter().enumerate() {
+            // A frame's subroutine is where the call in the frame below it
+            // went, and is named by its label if it has one.
+            let entry = match addresses.get(id + 1).map(|&call| disasm::decode(get_opcode(call, memory))) {
+                Some(Instruction::Call(entry)) => Some(entry as usize),
+                _ => None
+            };
+            let name = match entry {
+                Some(entry) => self.debug_info.subroutine_name(entry),
+                None => self.debug_info.symbol_for(address).map(|(label, _)| label).unwrap_or("main").to_string()
+            };
+            let mut frame = vec![
+                ("id", Json::from(id)),
+                ("name", Json::from(name)),
+                ("line", Json::from(0usize)),
+                ("column", Json::from(0usize)),
+                ("instructionPointerReference", Json::from(format!("0x{:03X}", address)))
+            ];
+            if let Some((path, line)) = self.debug_info.line_for_address(address) {
+                frame[2].1 = Json::from(i64::from(line));
+                frame.push(("source", object(vec![("path", Json::from(path))])));
+            }
+            frames.push(object(frame));
+        }
+        let total = frames.len();
+        let start = ::std::cmp::min(args.get("startFrame").as_i64().unwrap_or(0) as usize, total);
+        let levels = match args.get("levels").as_i64() {
+            Some(levels) if levels > 0 => levels as usize,
+            _ => total
+        };
+        let frames: Vec<Json> = frames.into_iter().skip(start).take(levels).collect();
+        object(vec![("stackFrames", Json::from(frames)), ("totalFrames", Json::from(total))])
+    }
+}
+
+pub fn disassemble(args: &Json, memory: &[u8], debug_info: &DebugInfo) -> Json {
+    let base = args.get("memoryReference").as_str().and_then(parse_number).unwrap_or(0);
+    let start = base.saturating_add(args.get("offset").as_i64().unwrap_or(0))
+        .saturating_add(args.get("instructionOffset").as_i64().unwrap_or(0).saturating_mul(2));
+    let count = args.get("instructionCount").as_i64().unwrap_or(0).min(MAX_INSTRUCTIONS);
+    let mut instructions = Vec::new();
+    for i in 0..count {
+        let address = start.saturating_add(2 * i);
+        if address < 0 || address >= memory.len() as i64 - 1 {
+            instructions.push(object(vec![
+                ("address", Json::from(format!("0x{:03X}", address.max(0)))),
+                ("instruction", Json::from("??"))
+            ]));
+            continue;
+        }
+        let opcode = get_opcode(address as usize, memory);
+        let mut instruction = vec![
+            ("address", Json::from(format!("0x{:03X}", address))),
+            ("instructionBytes", Json::from(format!("{:02X} {:02X}", opcode >> 8, opcode & 0xFF))),
+            ("instruction", Json::from(disasm::disassemble_with_labels(opcode, debug_info)))
+        ];
+        if let Some(label) = debug_info.label_at(address as usize) {
+            instruction.push(("symbol", Json::from(label)));
+        }
+        if let Some((path, line)) = debug_info.line_for_address(address as usize) {
+            instruction.push(("location", object(vec![("path", Json::from(path))])));
+            instruction.push(("line", Json::from(i64::from(line))));
+        }
+        instructions.push(object(instruction));
+    }
+    object(vec![("instructions", Json::from(instructions))])
+}
+
+fn variable(name: &str, value: String) -> Json {
+    object(vec![("name", Json::from(name)), ("value", Json::from(value)), ("variablesReference", Json::from(0usize))])
+}
+
+fn variables(args: &Json, state: &Chip8State) -> Json {
+    let mut variables = Vec::new();
+    match args.get("variablesReference").as_i64() {
+        Some(REGISTERS_REFERENCE) => {
+            for x in 0..16 {
+                variables.push(variable(&format!("V{:X}", x), format!("0x{:02X}", state.v[x])));
+            }
+            let mut index = variable("I", format!("0x{:03X}", state.index));
+            if let Json::Object(ref mut members) = index {
+                members.push(("memoryReference".to_string(), Json::from(format!("0x{:03X}", state.index))));
+            }
+            variables.push(index);
+            variables.push(variable("PC", format!("0x{:03X}", state.pc)));
+            variables.push(variable("SP", state.stack_pointer.to_string()));
+            variables.push(variable("DT", state.delay_timer.to_string()));
+            variables.push(variable("ST", state.sound_timer.to_string()));
+        }
+        Some(STACK_REFERENCE) => {
+            for depth in 0..state.stack_pointer {
+                variables.push(variable(&format!("[{}]", depth), format!("0x{:03X}", state.stack[depth])));
+            }
+        }
+        _ => ()
+    }
+    object(vec![("variables", Json::from(variables))])
+}
+
+fn set_variable(args: &Json, state: &mut Chip8State) -> Result<Json, String> {
+    let name = args.get("name").as_str().unwrap_or("");
+    let value = match args.get("value").as_str().and_then(parse_number) {
+        Some(value) if value >= 0 => value as usize,
+        _ => return Err("Expected a number such as 12 or 0x0C".to_string())
+    };
+    let shown = match name {
+        "I" => {
+            state.index = (value & 0xFFF) as u16;
+            format!("0x{:03X}", state.index)
+        }
+        "PC" => {
+            state.pc = value & 0xFFF;
+            format!("0x{:03X}", state.pc)
+        }
+        "DT" => {
+            state.delay_timer = (value & 0xFF) as u16;
+            state.delay_timer.to_string()
+        }
+        "ST" => {
+            state.sound_timer = (value & 0xFF) as u16;
+            state.sound_timer.to_string()
+        }
+        _ if name.len() == 2 && name.starts_with('V') => {
+            let x = usize::from_str_radix(&name[1..], 16).map_err(|_| format!("Unknown register '{}'", name))?;
+            state.v[x] = value as u8;
+            format!("0x{:02X}", state.v[x])
+        }
+        _ => return Err(format!("Register '{}' can't be changed", name))
+    };
+    Ok(object(vec![("value", Json::from(shown))]))
+}
+
+// The start of a memory request, from its reference and offset.
+fn memory_address(args: &Json) -> Option<i64> {
+    let base = args.get("memoryReference").as_str().and_then(parse_number)?;
+    base.checked_add(args.get("offset").as_i64().unwrap_or(0))
+}
+
+pub fn read_memory(args: &Json, memory: &[u8]) -> Result<Json, String> {
+    let address = memory_address(args).ok_or_else(|| "Invalid memory reference".to_string())?;
+    let count = args.get("count").as_i64().unwrap_or(0).max(0);
+    if address < 0 || address as usize >= memory.len() {
+        return Ok(object(vec![("address", Json::from(format!("0x{:03X}", address.max(0)))),
+                              ("unreadableBytes", Json::from(count))]));
+    }
+    let end = ::std::cmp::min(memory.len() as i64, address.saturating_add(count)) as usize;
+    let data = &memory[address as usize..end];
+    Ok(object(vec![
+        ("address", Json::from(format!("0x{:03X}", address))),
+        ("data", Json::from(base64_encode(data))),
+        ("unreadableBytes", Json::from(count - data.len() as i64))
+    ]))
+}
+
+fn write_memory(args: &Json, memory: &mut [u8]) -> Result<Json, String> {
+    let address = match memory_address(args) {
+        Some(address) if address >= 0 && (address as usize) < memory.len() => address as usize,
+        _ => return Err("Invalid memory reference".to_string())
+    };
+    let data = args.get("data").as_str().and_then(base64_decode).ok_or_else(|| "Invalid base64 data".to_string())?;
+    let end = ::std::cmp::min(memory.len(), address + data.len());
+    memory[address..end].copy_from_slice(&data[..end - address]);
+    Ok(object(vec![("bytesWritten", Json::from(end - address))]))
+}
+
+impl Monitor for DapServer {
+    fn before(&mut self, state: &mut Chip8State, memory: &mut Vec<u8>) {
+        if !self.configured {
+            while !self.configured {
+                let message = self.next_message();
+                self.handle(&message, state, memory);
+            }
+            if self.stop_on_entry {
+                self.stopped("entry", state, memory);
+                return;
+            }
+        }
+        // Requests can arrive while running, e.g. new breakpoints or pause.
+        while let Ok(message) = self.receiver.try_recv() {
+            self.handle(&message, state, memory);
+        }
+        let resuming = self.resuming;
+        self.resuming = false;
+        let reason = if self.pause_requested {
+            Some("pause")
+        } else if resuming {
+            None
+        } else if self.is_breakpoint(state.pc) {
+            Some("breakpoint")
+        } else {
+            match self.resume {
+                Resume::Run => None,
+                Resume::StepIn => Some("step"),
+                Resume::StepOver(pc, depth) if state.pc == pc && state.stack_pointer == depth => Some("step"),
+                Resume::StepOut(depth) if state.stack_pointer < depth => Some("step"),
+                _ => None
+            }
+        };
+        if let Some(reason) = reason {
+            self.stopped(reason, state, memory);
+        }
+    }
+}
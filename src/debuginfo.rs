@@ -0,0 +1,152 @@
+use std::fs::File;
+use std::io::prelude::*;
+use std::path::{Path, PathBuf};
+
+use config::parse_number;
+
+// Labels and source lines for a ROM, as an assembler would write them
+// alongside it. One entry per line:
+//
+//   label 0x200 main
+//   label 0x208 draw_player
+//   line 0x200 game.8o 12
+//   line 0x202 game.8o 13
+//
+// Relative file names are taken from the map file's directory.
+#[derive(Debug, Default, Clone, PartialEq)]
+pub struct DebugInfo {
+    // (address, name), sorted by address.
+    labels: Vec<(usize, String)>,
+    // (address, file, line), sorted by address.
+    lines: Vec<(usize, String, u32)>
+}
+
+fn parse_address(text: &str) -> Option<usize> {
+    match parse_number(text) {
+        Some(address) if address >= 0 && address < 0x1000 => Some(address as usize),
+        _ => None
+    }
+}
+
+pub fn parse_debug_info(text: &str, directory: &Path) -> Result<DebugInfo, String> {
+    let mut info: DebugInfo = Default::default();
+    for (line_number, raw_line) in text.lines().enumerate() {
+        let line = match raw_line.find('#') {
+            Some(comment) => &raw_line[..comment],
+            None => raw_line
+        };
+        let fields: Vec<&str> = line.split_whitespace().collect();
+        if fields.is_empty() {
+            continue;
+        }
+        let error = || format!("Debug info line {}: could not read '{}'", line_number + 1, raw_line);
+        match fields[0] {
+            "label" if fields.len() == 3 => {
+                let address = parse_address(fields[1]).ok_or_else(&error)?;
+                info.labels.push((address, fields[2].to_string()));
+            }
+            "line" if fields.len() == 4 => {
+                let address = parse_address(fields[1]).ok_or_else(&error)?;
+                let source_line = fields[3].parse().map_err(|_| error())?;
+                let path = directory.join(fields[2]).to_string_lossy().into_owned();
+                info.lines.push((address, path, source_line));
+            }
+            _ => return Err(error())
+        }
+    }
+    info.labels.sort_by_key(|&(address, _)| address);
+    info.lines.sort_by_key(|&(address, _, _)| address);
+    Ok(info)
+}
+
+pub fn load_debug_info(path: &str) -> Result<DebugInfo, String> {
+    let mut text = String::new();
+    match File::open(path) {
+        Ok(mut f) => {
+            if let Err(e) = f.read_to_string(&mut text) {
+                return Err(format!("Could not read debug info '{}': {}", path, e));
+            }
+        }
+        Err(e) => return Err(format!("Could not open debug info '{}': {}", path, e))
+    }
+    let directory = Path::new(path).parent().map(PathBuf::from).unwrap_or_default();
+    parse_debug_info(&text, &directory)
+}
+
+// Editors and map files may spell the same file differently, so one path
+// ending with the other is close enough.
+fn same_file(a: &str, b: &str) -> bool {
+    let a = Path::new(a);
+    let b = Path::new(b);
+    a.ends_with(b) || b.ends_with(a)
+}
+
+impl DebugInfo {
+    pub fn address_for_label(&self, name: &str) -> Option<usize> {
+        self.labels.iter().find(|&&(_, ref label)| label == name).map(|&(address, _)| address)
+    }
+
+    pub fn label_at(&self, address: usize) -> Option<&str> {
+        self.labels.iter().find(|&&(label_address, _)| label_address == address).map(|&(_, ref name)| &name[..])
+    }
+
+    // The closest label at or before an address, and how far past it the
+    // address is.
+    pub fn symbol_for(&self, address: usize) -> Option<(&str, usize)> {
+        self.labels.iter().rev()
+            .find(|&&(label_address, _)| label_address <= address)
+            .map(|&(label_address, ref name)| (&name[..], address - label_address))
+    }
+
+    // "draw_player+4", or the address when there is no label before it.
+    pub fn describe(&self, address: usize) -> String {
+        match self.symbol_for(address) {
+            Some((name, 0)) => name.to_string(),
+            Some((name, offset)) => format!("{}+{}", name, offset),
+            None => format!("0x{:03X}", address)
+        }
+    }
+
+    // A label name, "label+offset" or a number.
+    pub fn resolve(&self, text: &str) -> Option<usize> {
+        let mut parts = text.splitn(2, '+');
+        let base = parts.next()?.trim();
+        let address = match self.address_for_label(base) {
+            Some(address) => address,
+            None => return parse_address(text)
+        };
+        let offset = match parts.next() {
+            Some(offset) => parse_address(offset.trim())?,
+            None => 0
+        };
+        if address + offset < 0x1000 { Some(address + offset) } else { None }
+    }
+
+    // What to call the subroutine starting at an address.
+    pub fn subroutine_name(&self, entry: usize) -> String {
+        match self.label_at(entry) {
+            Some(label) => label.to_string(),
+            None => format!("sub_{:03X}", entry)
+        }
+    }
+
+    pub fn has_labels(&self) -> bool {
+        !self.labels.is_empty()
+    }
+
+    // The address of a source line, or of the next line with code on it,
+    // along with that line.
+    pub fn address_for_line(&self, file: &str, line: u32) -> Option<(usize, u32)> {
+        self.lines.iter()
+            .filter(|&&(_, ref path, source_line)| source_line >= line && same_file(path, file))
+            .min_by_key(|&&(address, _, source_line)| (source_line, address))
+            .map(|&(address, _, source_line)| (address, source_line))
+    }
+
+    // The source line of the instruction at an address.
+    pub fn line_for_address(&self, address: usize) -> Option<(&str, u32)> {
+        self.lines.iter()
+            .find(|&&(line_address, _, _)| line_address == address)
+            .map(|&(_, ref path, source_line)| (&path[..], source_line))
+    }
+}
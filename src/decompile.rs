@@ -0,0 +1,290 @@
+use std::collections::BTreeSet;
+
+use analysis::{self, is_skip, Analysis};
+use debuginfo::{self, DebugInfo};
+use disasm::{self, Instruction};
+
+// A line of output, with the address of the first instruction or byte it
+// stands for so labels can be put in front of it.
+struct Line {
+    address: Option<usize>,
+    depth: usize,
+    text: String,
+    // Data is kept a byte at a time until every label is known.
+    byte: Option<u8>
+}
+
+// Turns the blocks found by the analysis back into Octo: skips over single
+// instructions become `if ... then`, skips over forward jumps `if ... begin`
+// (with `else` where the body ends in a jump past it), and backward jumps
+// `loop ... again` with `while` for skips out of the loop. Anything else is
+// left as a jump, which Octo also has.
+struct Decompiler<'a> {
+    analysis: &'a Analysis,
+    debug_info: &'a DebugInfo,
+    // Everything jumped or called to. A jump that is a target itself can't
+    // be folded into a structured statement, as the label would be lost.
+    targets: BTreeSet<usize>,
+    // Addresses the output refers to by name, which need a label.
+    references: BTreeSet<usize>,
+    loops: BTreeSet<usize>,
+    lines: Vec<Line>
+}
+
+fn register(x: usize) -> String {
+    format!("v{:x}", x)
+}
+
+fn literal(n: u8) -> String {
+    if n < 10 { n.to_string() } else { format!("0x{:02X}", n) }
+}
+
+// When a skip instruction skips (`skips` true) or doesn't.
+fn condition(instruction: Instruction, skips: bool) -> String {
+    let (x, equal, operand) = match instruction {
+        Instruction::SkipEqual(x, nn) => (x, true, literal(nn)),
+        Instruction::SkipNotEqual(x, nn) => (x, false, literal(nn)),
+        Instruction::SkipEqualReg(x, y) => (x, true, register(y)),
+        Instruction::SkipNotEqualReg(x, y) => (x, false, register(y)),
+        Instruction::SkipKey(x) => return format!("{} {}", register(x), if skips { "key" } else { "-key" }),
+        Instruction::SkipNotKey(x) => return format!("{} {}", register(x), if skips { "-key" } else { "key" }),
+        _ => unreachable!()
+    };
+    format!("{} {} {}", register(x), if equal == skips { "==" } else { "!=" }, operand)
+}
+
+impl<'a> Decompiler<'a> {
+    fn in_rom(&self, address: usize) -> bool {
+        address >= 0x200 && address < 0x200 + self.analysis.rom.len()
+    }
+
+    fn decode(&self, address: usize) -> Instruction {
+        disasm::decode(self.analysis.opcode(address))
+    }
+
+    fn name(&self, address: usize) -> String {
+        if address == 0x200 {
+            return "main".to_string();
+        }
+        match self.debug_info.label_at(address) {
+            Some(label) => label.to_string(),
+            None if self.analysis.subroutines.contains(&address) => self.debug_info.subroutine_name(address),
+            None if self.analysis.is_instruction(address) => format!("label_{:03X}", address),
+            None => format!("data_{:03X}", address)
+        }
+    }
+
+    // An address as the output refers to it: by name in the ROM, and as a
+    // number outside it, such as the font.
+    fn target(&mut self, address: usize) -> String {
+        if self.in_rom(address) {
+            self.references.insert(address);
+            self.name(address)
+        } else {
+            format!("0x{:03X}", address)
+        }
+    }
+
+    fn line(&mut self, address: Option<usize>, depth: usize, text: String) {
+        self.lines.push(Line { address, depth, text, byte: None });
+    }
+
+    fn statement(&mut self, instruction: Instruction) -> String {
+        match instruction {
+            Instruction::Clear => "clear".to_string(),
+            Instruction::Return => "return".to_string(),
+            Instruction::Jump(nnn) => format!("jump {}", self.target(nnn as usize)),
+            Instruction::Call(nnn) => self.target(nnn as usize),
+            Instruction::Load(x, nn) => format!("{} := {}", register(x), literal(nn)),
+            Instruction::Add(x, nn) => format!("{} += {}", register(x), literal(nn)),
+            Instruction::Move(x, y) => format!("{} := {}", register(x), register(y)),
+            Instruction::Or(x, y) => format!("{} |= {}", register(x), register(y)),
+            Instruction::And(x, y) => format!("{} &= {}", register(x), register(y)),
+            Instruction::Xor(x, y) => format!("{} ^= {}", register(x), register(y)),
+            Instruction::AddReg(x, y) => format!("{} += {}", register(x), register(y)),
+            Instruction::Sub(x, y) => format!("{} -= {}", register(x), register(y)),
+            Instruction::ShiftRight(x, y) => format!("{} >>= {}", register(x), register(y)),
+            Instruction::SubReverse(x, y) => format!("{} =- {}", register(x), register(y)),
+            Instruction::ShiftLeft(x, y) => format!("{} <<= {}", register(x), register(y)),
+            Instruction::LoadIndex(nnn) => format!("i := {}", self.target(nnn as usize)),
+            Instruction::JumpOffset(nnn) => format!("jump0 {}", self.target(nnn as usize)),
+            Instruction::Random(x, nn) => format!("{} := random {}", register(x), literal(nn)),
+            Instruction::Draw(x, y, n) => format!("sprite {} {} {}", register(x), register(y), n),
+            Instruction::ReadDelay(x) => format!("{} := delay", register(x)),
+            Instruction::WaitKey(x) => format!("{} := key", register(x)),
+            Instruction::SetDelay(x) => format!("delay := {}", register(x)),
+            Instruction::SetSound(x) => format!("buzzer := {}", register(x)),
+            Instruction::AddIndex(x) => format!("i += {}", register(x)),
+            Instruction::Font(x) => format!("i := hex {}", register(x)),
+            Instruction::Bcd(x) => format!("bcd {}", register(x)),
+            Instruction::Store(x) => format!("save {}", register(x)),
+            Instruction::Restore(x) => format!("load {}", register(x)),
+            // Skips are conditions, and the analysis never takes anything
+            // else for code, but show them as Octo would write the bytes.
+            _ => {
+                let opcode = match instruction {
+                    Instruction::System(opcode) | Instruction::Unknown(opcode) => opcode,
+                    _ => return format!("if {} then", condition(instruction, false))
+                };
+                format!("0x{:02X} 0x{:02X}", opcode >> 8, opcode & 0xFF)
+            }
+        }
+    }
+
+    // The code from `start` up to `end`, with `inside` the head and exit of
+    // the loop it's in.
+    fn code(&mut self, start: usize, end: usize, depth: usize, inside: Option<(usize, usize)>) {
+        let mut address = start;
+        while address < end {
+            // A loop runs from here to the last jump back here.
+            if !self.loops.contains(&address) {
+                let back = (address..end).step_by(2).rev()
+                    .find(|&from| self.decode(from) == Instruction::Jump(address as u16));
+                if let Some(back) = back {
+                    self.loops.insert(address);
+                    self.line(Some(address), depth, "loop".to_string());
+                    self.code(address, back, depth + 1, Some((address, back + 2)));
+                    self.line(Some(back), depth, "again".to_string());
+                    address = back + 2;
+                    continue;
+                }
+            }
+
+            let instruction = self.decode(address);
+            let next = address + 2;
+            if is_skip(instruction) && next < end && !self.targets.contains(&next) {
+                let next_instruction = self.decode(next);
+                if let Instruction::Jump(target) = next_instruction {
+                    let target = target as usize;
+                    if inside.map(|(_, exit)| exit) == Some(target) {
+                        self.line(Some(address), depth, format!("while {}", condition(instruction, true)));
+                        address = next + 2;
+                        continue;
+                    }
+                    if target > next + 2 && target <= end {
+                        // The body may end by jumping over an else.
+                        let mut body_end = target;
+                        let mut after = target;
+                        let jump = target - 2;
+                        if jump > next && !self.targets.contains(&jump) {
+                            if let Instruction::Jump(past) = self.decode(jump) {
+                                if past as usize > target && past as usize <= end {
+                                    body_end = jump;
+                                    after = past as usize;
+                                }
+                            }
+                        }
+                        self.line(Some(address), depth, format!("if {} begin", condition(instruction, true)));
+                        self.code(next + 2, body_end, depth + 1, inside);
+                        if after != target {
+                            self.line(None, depth, "else".to_string());
+                            self.code(target, after, depth + 1, inside);
+                        }
+                        self.line(None, depth, "end".to_string());
+                        address = after;
+                        continue;
+                    }
+                }
+                if !is_skip(next_instruction) {
+                    let text = format!("if {} then {}", condition(instruction, false), self.statement(next_instruction));
+                    self.line(Some(address), depth, text);
+                    address = next + 2;
+                    continue;
+                }
+            }
+            let text = self.statement(instruction);
+            self.line(Some(address), depth, text);
+            address = next;
+        }
+    }
+
+    fn decompile(&mut self) -> String {
+        let end = 0x200 + self.analysis.rom.len();
+        self.references.insert(0x200);
+        let mut address = 0x200;
+        while address < end {
+            if self.analysis.is_instruction(address) {
+                let mut code_end = address;
+                while self.analysis.is_instruction(code_end) {
+                    code_end += 2;
+                }
+                self.code(address, code_end, 1, None);
+                address = code_end;
+            } else {
+                let byte = self.analysis.rom[address - 0x200];
+                self.lines.push(Line { address: Some(address), depth: 1, text: String::new(), byte: Some(byte) });
+                address += 1;
+            }
+        }
+
+        let mut output = String::from("# Decompiled by rust_chip8\n");
+        for note in &self.analysis.notes {
+            output.push_str(&format!("# {}\n", analysis::describe_note(note, self.debug_info)));
+        }
+        let mut labelled = BTreeSet::new();
+        let mut i = 0;
+        while i < self.lines.len() {
+            if let Some(address) = self.lines[i].address {
+                if self.references.contains(&address) && labelled.insert(address) {
+                    output.push_str(&format!("\n: {}\n", self.name(address)));
+                }
+            }
+            let indent = "  ".repeat(self.lines[i].depth);
+            if self.lines[i].byte.is_none() {
+                output.push_str(&format!("{}{}\n", indent, self.lines[i].text));
+                i += 1;
+                continue;
+            }
+            // Up to 8 bytes a line, starting a new one at each label.
+            let mut bytes = Vec::new();
+            while let Some(&Line { address: Some(address), byte: Some(byte), .. }) = self.lines.get(i) {
+                if bytes.len() == 8 || (!bytes.is_empty() && self.references.contains(&address)) {
+                    break;
+                }
+                bytes.push(format!("0x{:02X}", byte));
+                i += 1;
+            }
+            output.push_str(&format!("{}{}\n", indent, bytes.join(" ")));
+        }
+        output
+    }
+}
+
+pub fn decompile(analysis: &Analysis, debug_info: &DebugInfo) -> String {
+    let mut targets = BTreeSet::new();
+    for block in analysis.blocks.values() {
+        for address in (block.start..block.end).step_by(2) {
+            match disasm::decode(analysis.opcode(address)) {
+                Instruction::Jump(nnn) | Instruction::Call(nnn) | Instruction::JumpOffset(nnn) => {
+                    targets.insert(nnn as usize);
+                }
+                _ => ()
+            }
+        }
+    }
+    let mut decompiler = Decompiler {
+        analysis,
+        debug_info,
+        targets,
+        references: BTreeSet::new(),
+        loops: BTreeSet::new(),
+        lines: Vec::new()
+    };
+    decompiler.decompile()
+}
+
+pub fn decompile_command(args: &[String]) -> Result<(), String> {
+    let usage = "Usage: rust_chip8 decompile [--debug-info <file>] <rom>";
+    let (debug_info_path, rom_path) = match args.len() {
+        1 => (None, &args[0]),
+        3 if args[0] == "--debug-info" => (Some(&args[1]), &args[2]),
+        _ => return Err(usage.to_string())
+    };
+    let debug_info = match debug_info_path {
+        Some(path) => debuginfo::load_debug_info(path)?,
+        None => Default::default()
+    };
+    let analysis = analysis::analyze(&analysis::read_rom(rom_path)?);
+    print!("{}", decompile(&analysis, &debug_info));
+    Ok(())
+}
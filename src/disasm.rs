@@ -0,0 +1,251 @@
+use std::fmt;
+use std::ops::Range;
+
+use debuginfo::DebugInfo;
+
+// A decoded CHIP-8 instruction. Registers are given by number and
+// addresses and constants as they appear in the opcode.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Instruction {
+    Clear,                          // 00E0
+    Return,                         // 00EE
+    System(u16),                    // 0NNN
+    Jump(u16),                      // 1NNN
+    Call(u16),                      // 2NNN
+    SkipEqual(usize, u8),           // 3XNN
+    SkipNotEqual(usize, u8),        // 4XNN
+    SkipEqualReg(usize, usize),     // 5XY0
+    Load(usize, u8),                // 6XNN
+    Add(usize, u8),                 // 7XNN
+    Move(usize, usize),             // 8XY0
+    Or(usize, usize),               // 8XY1
+    And(usize, usize),              // 8XY2
+    Xor(usize, usize),              // 8XY3
+    AddReg(usize, usize),           // 8XY4
+    Sub(usize, usize),              // 8XY5
+    ShiftRight(usize, usize),       // 8XY6
+    SubReverse(usize, usize),       // 8XY7
+    ShiftLeft(usize, usize),        // 8XYE
+    SkipNotEqualReg(usize, usize),  // 9XY0
+    LoadIndex(u16),                 // ANNN
+    JumpOffset(u16),                // BNNN
+    Random(usize, u8),              // CXNN
+    Draw(usize, usize, u8),         // DXYN
+    SkipKey(usize),                 // EX9E
+    SkipNotKey(usize),              // EXA1
+    ReadDelay(usize),               // FX07
+    WaitKey(usize),                 // FX0A
+    SetDelay(usize),                // FX15
+    SetSound(usize),                // FX18
+    AddIndex(usize),                // FX1E
+    Font(usize),                    // FX29
+    Bcd(usize),                     // FX33
+    Store(usize),                   // FX55
+    Restore(usize),                 // FX65
+    Unknown(u16)
+}
+
+// Groups of instructions, for filtering traces and the like.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Class {
+    // Jumps, calls and returns.
+    Flow,
+    // Conditional skips on registers.
+    Skip,
+    // Arithmetic and loads into V registers.
+    Alu,
+    // I and memory.
+    Memory,
+    Draw,
+    Random,
+    Key,
+    Timer,
+    Unknown
+}
+
+const CLASS_NAMES: [(&str, Class); 9] = [
+    ("flow", Class::Flow), ("skip", Class::Skip), ("alu", Class::Alu), ("memory", Class::Memory),
+    ("draw", Class::Draw), ("random", Class::Random), ("key", Class::Key), ("timer", Class::Timer),
+    ("unknown", Class::Unknown)
+];
+
+pub fn parse_class(name: &str) -> Result<Class, String> {
+    for &(class_name, class) in CLASS_NAMES.iter() {
+        if class_name == name.to_lowercase() {
+            return Ok(class);
+        }
+    }
+    let names: Vec<&str> = CLASS_NAMES.iter().map(|&(class_name, _)| class_name).collect();
+    Err(format!("Unknown instruction class '{}', expected one of: {}", name, names.join(", ")))
+}
+
+pub fn decode(opcode: u16) -> Instruction {
+    let x = ((opcode & 0x0F00) >> 8) as usize;
+    let y = ((opcode & 0x00F0) >> 4) as usize;
+    let n = (opcode & 0x000F) as u8;
+    let nn = (opcode & 0x00FF) as u8;
+    let nnn = opcode & 0x0FFF;
+    match opcode & 0xF000 {
+        0x0000 => {
+            match opcode {
+                0x00E0 => Instruction::Clear,
+                0x00EE => Instruction::Return,
+                _ => Instruction::System(nnn)
+            }
+        }
+        0x1000 => Instruction::Jump(nnn),
+        0x2000 => Instruction::Call(nnn),
+        0x3000 => Instruction::SkipEqual(x, nn),
+        0x4000 => Instruction::SkipNotEqual(x, nn),
+        0x5000 if n == 0 => Instruction::SkipEqualReg(x, y),
+        0x6000 => Instruction::Load(x, nn),
+        0x7000 => Instruction::Add(x, nn),
+        0x8000 => {
+            match n {
+                0x0 => Instruction::Move(x, y),
+                0x1 => Instruction::Or(x, y),
+                0x2 => Instruction::And(x, y),
+                0x3 => Instruction::Xor(x, y),
+                0x4 => Instruction::AddReg(x, y),
+                0x5 => Instruction::Sub(x, y),
+                0x6 => Instruction::ShiftRight(x, y),
+                0x7 => Instruction::SubReverse(x, y),
+                0xE => Instruction::ShiftLeft(x, y),
+                _ => Instruction::Unknown(opcode)
+            }
+        }
+        0x9000 if n == 0 => Instruction::SkipNotEqualReg(x, y),
+        0xA000 => Instruction::LoadIndex(nnn),
+        0xB000 => Instruction::JumpOffset(nnn),
+        0xC000 => Instruction::Random(x, nn),
+        0xD000 => Instruction::Draw(x, y, n),
+        0xE000 => {
+            match nn {
+                0x9E => Instruction::SkipKey(x),
+                0xA1 => Instruction::SkipNotKey(x),
+                _ => Instruction::Unknown(opcode)
+            }
+        }
+        0xF000 => {
+            match nn {
+                0x07 => Instruction::ReadDelay(x),
+                0x0A => Instruction::WaitKey(x),
+                0x15 => Instruction::SetDelay(x),
+                0x18 => Instruction::SetSound(x),
+                0x1E => Instruction::AddIndex(x),
+                0x29 => Instruction::Font(x),
+                0x33 => Instruction::Bcd(x),
+                0x55 => Instruction::Store(x),
+                0x65 => Instruction::Restore(x),
+                _ => Instruction::Unknown(opcode)
+            }
+        }
+        _ => Instruction::Unknown(opcode)
+    }
+}
+
+impl Instruction {
+    pub fn class(&self) -> Class {
+        match *self {
+            Instruction::Return | Instruction::System(_) | Instruction::Jump(_) |
+            Instruction::Call(_) | Instruction::JumpOffset(_) => Class::Flow,
+            Instruction::SkipEqual(..) | Instruction::SkipNotEqual(..) |
+            Instruction::SkipEqualReg(..) | Instruction::SkipNotEqualReg(..) => Class::Skip,
+            Instruction::Load(..) | Instruction::Add(..) | Instruction::Move(..) |
+            Instruction::Or(..) | Instruction::And(..) | Instruction::Xor(..) |
+            Instruction::AddReg(..) | Instruction::Sub(..) | Instruction::ShiftRight(..) |
+            Instruction::SubReverse(..) | Instruction::ShiftLeft(..) => Class::Alu,
+            Instruction::LoadIndex(_) | Instruction::AddIndex(_) | Instruction::Font(_) |
+            Instruction::Bcd(_) | Instruction::Store(_) | Instruction::Restore(_) => Class::Memory,
+            Instruction::Clear | Instruction::Draw(..) => Class::Draw,
+            Instruction::Random(..) => Class::Random,
+            Instruction::SkipKey(_) | Instruction::SkipNotKey(_) | Instruction::WaitKey(_) => Class::Key,
+            Instruction::ReadDelay(_) | Instruction::SetDelay(_) | Instruction::SetSound(_) => Class::Timer,
+            Instruction::Unknown(_) => Class::Unknown
+        }
+    }
+
+    // The memory the instruction reads when I holds `index`, besides its
+    // own opcode. The range may run past the end of memory.
+    pub fn memory_reads(&self, index: usize) -> Range<usize> {
+        match *self {
+            Instruction::Draw(_, _, n) => index..index + n as usize,
+            Instruction::Restore(x) => index..index + x + 1,
+            _ => index..index
+        }
+    }
+
+    // The memory the instruction writes when I holds `index`.
+    pub fn memory_writes(&self, index: usize) -> Range<usize> {
+        match *self {
+            Instruction::Bcd(_) => index..index + 3,
+            Instruction::Store(x) => index..index + x + 1,
+            _ => index..index
+        }
+    }
+}
+
+// Cowgod's mnemonics, e.g. "LD V3, #1F" and "DRW V0, V1, 5".
+impl fmt::Display for Instruction {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match *self {
+            Instruction::Clear => write!(f, "CLS"),
+            Instruction::Return => write!(f, "RET"),
+            Instruction::System(nnn) => write!(f, "SYS #{:03X}", nnn),
+            Instruction::Jump(nnn) => write!(f, "JP #{:03X}", nnn),
+            Instruction::Call(nnn) => write!(f, "CALL #{:03X}", nnn),
+            Instruction::SkipEqual(x, nn) => write!(f, "SE V{:X}, #{:02X}", x, nn),
+            Instruction::SkipNotEqual(x, nn) => write!(f, "SNE V{:X}, #{:02X}", x, nn),
+            Instruction::SkipEqualReg(x, y) => write!(f, "SE V{:X}, V{:X}", x, y),
+            Instruction::Load(x, nn) => write!(f, "LD V{:X}, #{:02X}", x, nn),
+            Instruction::Add(x, nn) => write!(f, "ADD V{:X}, #{:02X}", x, nn),
+            Instruction::Move(x, y) => write!(f, "LD V{:X}, V{:X}", x, y),
+            Instruction::Or(x, y) => write!(f, "OR V{:X}, V{:X}", x, y),
+            Instruction::And(x, y) => write!(f, "AND V{:X}, V{:X}", x, y),
+            Instruction::Xor(x, y) => write!(f, "XOR V{:X}, V{:X}", x, y),
+            Instruction::AddReg(x, y) => write!(f, "ADD V{:X}, V{:X}", x, y),
+            Instruction::Sub(x, y) => write!(f, "SUB V{:X}, V{:X}", x, y),
+            Instruction::ShiftRight(x, y) => write!(f, "SHR V{:X}, V{:X}", x, y),
+            Instruction::SubReverse(x, y) => write!(f, "SUBN V{:X}, V{:X}", x, y),
+            Instruction::ShiftLeft(x, y) => write!(f, "SHL V{:X}, V{:X}", x, y),
+            Instruction::SkipNotEqualReg(x, y) => write!(f, "SNE V{:X}, V{:X}", x, y),
+            Instruction::LoadIndex(nnn) => write!(f, "LD I, #{:03X}", nnn),
+            Instruction::JumpOffset(nnn) => write!(f, "JP V0, #{:03X}", nnn),
+            Instruction::Random(x, nn) => write!(f, "RND V{:X}, #{:02X}", x, nn),
+            Instruction::Draw(x, y, n) => write!(f, "DRW V{:X}, V{:X}, {}", x, y, n),
+            Instruction::SkipKey(x) => write!(f, "SKP V{:X}", x),
+            Instruction::SkipNotKey(x) => write!(f, "SKNP V{:X}", x),
+            Instruction::ReadDelay(x) => write!(f, "LD V{:X}, DT", x),
+            Instruction::WaitKey(x) => write!(f, "LD V{:X}, K", x),
+            Instruction::SetDelay(x) => write!(f, "LD DT, V{:X}", x),
+            Instruction::SetSound(x) => write!(f, "LD ST, V{:X}", x),
+            Instruction::AddIndex(x) => write!(f, "ADD I, V{:X}", x),
+            Instruction::Font(x) => write!(f, "LD F, V{:X}", x),
+            Instruction::Bcd(x) => write!(f, "LD B, V{:X}", x),
+            Instruction::Store(x) => write!(f, "LD [I], V{:X}", x),
+            Instruction::Restore(x) => write!(f, "LD V{:X}, [I]", x),
+            Instruction::Unknown(opcode) => write!(f, "DW #{:04X}", opcode)
+        }
+    }
+}
+
+pub fn disassemble(opcode: u16) -> String {
+    decode(opcode).to_string()
+}
+
+// Like disassemble, but with jump, call and index targets shown by label
+// where there is one.
+pub fn disassemble_with_labels(opcode: u16, debug_info: &DebugInfo) -> String {
+    let instruction = decode(opcode);
+    let (mnemonic, target) = match instruction {
+        Instruction::Jump(nnn) => ("JP", nnn),
+        Instruction::Call(nnn) => ("CALL", nnn),
+        Instruction::LoadIndex(nnn) => ("LD I,", nnn),
+        Instruction::JumpOffset(nnn) => ("JP V0,", nnn),
+        _ => return instruction.to_string()
+    };
+    match debug_info.label_at(target as usize) {
+        Some(label) => format!("{} {}", mnemonic, label),
+        None => instruction.to_string()
+    }
+}
@@ -0,0 +1,241 @@
+use {Chip8State, emulate_cycle, execute_opcode, get_opcode};
+use disasm::{self, Class, Instruction};
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Engine {
+    // Fetch and decode every instruction as it runs.
+    Interpreter,
+    // Decode each instruction once and keep it by address.
+    Predecoded,
+    // Decode runs of instructions once and keep them by address.
+    Cached
+}
+
+pub fn parse_engine(text: &str) -> Result<Engine, String> {
+    match text {
+        "interpreter" => Ok(Engine::Interpreter),
+        "predecoded" => Ok(Engine::Predecoded),
+        "cached" => Ok(Engine::Cached),
+        _ => Err(format!("Unknown engine '{}', expected interpreter, predecoded or cached", text))
+    }
+}
+
+// Something that runs instructions in place of the interpreter, returning
+// whether one drew. It may run any number of them up to `budget`.
+pub trait Cache {
+    fn run(&mut self, state: &mut Chip8State, memory: &mut Vec<u8>, budget: &mut u32) -> bool;
+}
+
+pub fn create_cache(engine: Engine) -> Option<Box<dyn Cache>> {
+    match engine {
+        Engine::Interpreter => None,
+        Engine::Predecoded => Some(Box::new(PredecodeCache::default())),
+        Engine::Cached => Some(Box::new(BlockCache::default()))
+    }
+}
+
+// An instruction as fetched and decoded, keeping the opcode for the
+// interpreter.
+#[derive(Debug, Clone, Copy, PartialEq)]
+struct Decoded {
+    opcode: u16,
+    instruction: Instruction
+}
+
+fn fetch(address: usize, memory: &[u8]) -> Decoded {
+    let opcode = get_opcode(address, memory);
+    Decoded { opcode, instruction: disasm::decode(opcode) }
+}
+
+// Runs a decoded instruction at state.pc, returning whether it drew. The
+// simple ones are carried out directly, and the rest go through the
+// interpreter so they behave exactly the same.
+fn execute(decoded: Decoded, state: &mut Chip8State, memory: &mut Vec<u8>) -> bool {
+    let next = state.pc + 2;
+    match decoded.instruction {
+        Instruction::Load(x, nn) => state.v[x] = nn,
+        Instruction::Add(x, nn) => state.v[x] = state.v[x].wrapping_add(nn),
+        Instruction::Move(x, y) => state.v[x] = state.v[y],
+        Instruction::Or(x, y) => state.v[x] |= state.v[y],
+        Instruction::And(x, y) => state.v[x] &= state.v[y],
+        Instruction::Xor(x, y) => state.v[x] ^= state.v[y],
+        Instruction::LoadIndex(nnn) => state.index = nnn,
+        Instruction::AddIndex(x) => state.index += u16::from(state.v[x]),
+        Instruction::Font(x) => state.index = u16::from(state.v[x]) * 5,
+        Instruction::ReadDelay(x) => state.v[x] = state.delay_timer as u8,
+        Instruction::SetDelay(x) => state.delay_timer = u16::from(state.v[x]),
+        Instruction::Jump(nnn) => {
+            state.pc = nnn as usize;
+            return false;
+        }
+        Instruction::Call(nnn) => {
+            state.stack[state.stack_pointer] = state.pc;
+            state.stack_pointer += 1;
+            state.pc = nnn as usize;
+            return false;
+        }
+        Instruction::Return => {
+            state.stack_pointer -= 1;
+            state.pc = state.stack[state.stack_pointer] + 2;
+            return false;
+        }
+        Instruction::SkipEqual(x, nn) if state.v[x] == nn => {
+            state.pc = next + 2;
+            return false;
+        }
+        Instruction::SkipNotEqual(x, nn) if state.v[x] != nn => {
+            state.pc = next + 2;
+            return false;
+        }
+        Instruction::SkipEqualReg(x, y) if state.v[x] == state.v[y] => {
+            state.pc = next + 2;
+            return false;
+        }
+        Instruction::SkipEqual(..) | Instruction::SkipNotEqual(..) | Instruction::SkipEqualReg(..) => (),
+        _ => return execute_opcode(decoded.opcode, state, memory)
+    }
+    state.pc = next;
+    false
+}
+
+// The decoded instruction at each even address. Instructions at odd
+// addresses are rare enough to decode every time.
+#[derive(Default)]
+pub struct PredecodeCache {
+    instructions: Vec<Option<Decoded>>
+}
+
+impl Cache for PredecodeCache {
+    fn run(&mut self, state: &mut Chip8State, memory: &mut Vec<u8>, budget: &mut u32) -> bool {
+        if self.instructions.is_empty() {
+            self.instructions = vec![None; memory.len() / 2];
+        }
+        while *budget > 0 {
+            let decoded = if state.pc & 1 == 0 {
+                *self.instructions[state.pc / 2].get_or_insert_with(|| fetch(state.pc, memory))
+            } else {
+                fetch(state.pc, memory)
+            };
+            let writes = decoded.instruction.memory_writes(state.index as usize);
+            state.cycles += 1;
+            *budget -= 1;
+            let drawn = execute(decoded, state, memory);
+            for address in writes {
+                if address / 2 < self.instructions.len() {
+                    self.instructions[address / 2] = None;
+                }
+            }
+            if drawn {
+                return true;
+            }
+        }
+        false
+    }
+}
+
+// Longest block to decode, so invalidating a write only has to look this
+// far back for blocks covering it.
+const MAX_BLOCK_LENGTH: usize = 32;
+
+// Whether control can go anywhere but the next instruction, or the
+// instruction may write over its own block.
+fn ends_block(instruction: Instruction) -> bool {
+    match instruction.class() {
+        Class::Flow | Class::Skip | Class::Key => true,
+        _ => !instruction.memory_writes(0).is_empty()
+    }
+}
+
+struct Block {
+    // The address after the last instruction.
+    end: usize,
+    instructions: Vec<Decoded>
+}
+
+#[derive(Default)]
+pub struct BlockCache {
+    // Blocks by start address.
+    blocks: Vec<Option<Block>>,
+    // Bytes that have ever been decoded into a block, so writes to data
+    // don't have to look for blocks to forget.
+    decoded: Vec<bool>
+}
+
+impl BlockCache {
+    fn block(&mut self, start: usize, memory: &[u8]) -> &Block {
+        if self.blocks.is_empty() {
+            self.blocks = (0..memory.len()).map(|_| None).collect();
+            self.decoded = vec![false; memory.len()];
+        }
+        if self.blocks[start].is_none() {
+            let mut instructions = Vec::new();
+            let mut address = start;
+            while address + 1 < memory.len() && instructions.len() < MAX_BLOCK_LENGTH {
+                let decoded = fetch(address, memory);
+                instructions.push(decoded);
+                address += 2;
+                if ends_block(decoded.instruction) {
+                    break;
+                }
+            }
+            for byte in &mut self.decoded[start..address] {
+                *byte = true;
+            }
+            self.blocks[start] = Some(Block { end: address, instructions });
+        }
+        self.blocks[start].as_ref().unwrap()
+    }
+
+    // Forgets every block covering memory from `start` to `end`.
+    fn invalidate(&mut self, start: usize, end: usize) {
+        let end = ::std::cmp::min(end, self.blocks.len());
+        let start = ::std::cmp::min(start, end);
+        if !self.decoded[start..end].contains(&true) {
+            return;
+        }
+        let first = start.saturating_sub(MAX_BLOCK_LENGTH * 2);
+        for address in first..end {
+            if self.blocks[address].as_ref().is_some_and(|block| block.end > start) {
+                self.blocks[address] = None;
+            }
+        }
+    }
+}
+
+impl Cache for BlockCache {
+    // Runs a block at a time, each until control leaves it or an
+    // instruction writes memory, until one draws or `budget` runs out.
+    fn run(&mut self, state: &mut Chip8State, memory: &mut Vec<u8>, budget: &mut u32) -> bool {
+        while *budget > 0 {
+            let start = state.pc;
+            let length = self.block(start, memory).instructions.len();
+            if length == 0 {
+                // There isn't a whole instruction left before the end of
+                // memory, so fail the way the interpreter does.
+                *budget -= 1;
+                return emulate_cycle(state, memory);
+            }
+            for i in 0..length {
+                // Writes end the block, so it is still there to read from.
+                let decoded = self.blocks[start].as_ref().unwrap().instructions[i];
+                let next = state.pc + 2;
+                let writes = decoded.instruction.memory_writes(state.index as usize);
+                state.cycles += 1;
+                *budget -= 1;
+                if execute(decoded, state, memory) {
+                    return true;
+                }
+                if !writes.is_empty() {
+                    self.invalidate(writes.start, writes.end);
+                    break;
+                }
+                // The interpreter skips on some opcodes the decoder doesn't
+                // take for skips, such as 5XY1.
+                if state.pc != next || *budget == 0 {
+                    break;
+                }
+            }
+        }
+        false
+    }
+}
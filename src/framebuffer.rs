@@ -0,0 +1,111 @@
+use std::fmt;
+
+use movie;
+
+pub const WIDTH: usize = 64;
+pub const HEIGHT: usize = 32;
+
+// Part of a row, from `x` for `width` pixels.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct Span {
+    pub y: usize,
+    pub x: usize,
+    pub width: usize
+}
+
+// The screen, a bit per pixel and a u64 per row with the leftmost pixel in
+// the top bit, so a sprite row is drawn with a shift and an XOR and its
+// collisions found with an AND. Pixels changed since the screen was last
+// presented are kept the same way, for frontends that only send changes.
+// They are left out of comparisons and debug output, which are about what
+// is on the screen.
+#[derive(Clone)]
+pub struct Framebuffer {
+    rows: Vec<u64>,
+    dirty: Vec<u64>
+}
+
+impl PartialEq for Framebuffer {
+    fn eq(&self, other: &Framebuffer) -> bool {
+        self.rows == other.rows
+    }
+}
+
+impl fmt::Debug for Framebuffer {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.debug_struct("Framebuffer").field("rows", &self.rows).finish()
+    }
+}
+
+impl Default for Framebuffer {
+    // Nothing has been presented yet, so all of it needs to be.
+    fn default() -> Framebuffer {
+        Framebuffer { rows: vec![0; HEIGHT], dirty: vec![!0; HEIGHT] }
+    }
+}
+
+impl Framebuffer {
+    pub fn clear(&mut self) {
+        for (row, dirty) in self.rows.iter_mut().zip(self.dirty.iter_mut()) {
+            *dirty |= *row;
+            *row = 0;
+        }
+    }
+
+    // 1 if the pixel is lit, else 0.
+    pub fn pixel(&self, x: usize, y: usize) -> u8 {
+        ((self.rows[y] >> (WIDTH - 1 - x)) & 1) as u8
+    }
+
+    // XORs a row of `bits` onto row `y`, returning whether a lit pixel
+    // went dark.
+    fn xor_row(&mut self, y: usize, bits: u64) -> bool {
+        let collided = self.rows[y] & bits != 0;
+        self.rows[y] ^= bits;
+        self.dirty[y] |= bits;
+        collided
+    }
+
+    // XORs a sprite onto the screen, a byte a row, with its top left pixel
+    // at (x, y) wrapped onto the screen, returning whether a lit pixel went
+    // dark. Pixels past the right or bottom edge wrap around when `wrap` is
+    // set, and are cut off otherwise.
+    pub fn draw_sprite(&mut self, x: usize, y: usize, sprite: &[u8], wrap: bool) -> bool {
+        let (x, y) = (x % WIDTH, y % HEIGHT);
+        let mut collided = false;
+        for (i, &bits) in sprite.iter().enumerate() {
+            let mut row = y + i;
+            if row >= HEIGHT {
+                if !wrap {
+                    break;
+                }
+                row %= HEIGHT;
+            }
+            let bits = u64::from(bits) << (WIDTH - 8);
+            let bits = if wrap { bits.rotate_right(x as u32) } else { bits >> x };
+            collided |= self.xor_row(row, bits);
+        }
+        collided
+    }
+
+    // What changed since the last call, as a span on each changed row from
+    // its first changed pixel to its last.
+    pub fn take_dirty(&mut self) -> Vec<Span> {
+        let mut spans = Vec::new();
+        for (y, dirty) in self.dirty.iter_mut().enumerate() {
+            if *dirty != 0 {
+                let x = dirty.leading_zeros() as usize;
+                let width = WIDTH - x - dirty.trailing_zeros() as usize;
+                spans.push(Span { y, x, width });
+                *dirty = 0;
+            }
+        }
+        spans
+    }
+
+    // Hashed a byte a pixel, so hashes in movies and traces recorded before
+    // the screen was packed still match.
+    pub fn hash(&self) -> u64 {
+        movie::hash_bytes((0..HEIGHT).flat_map(|y| (0..WIDTH).map(move |x| self.pixel(x, y))))
+    }
+}
@@ -0,0 +1,426 @@
+use std::io::prelude::*;
+use std::net::{TcpListener, TcpStream};
+
+use debuginfo::DebugInfo;
+use monitor::Monitor;
+use Chip8State;
+
+// Registers as GDB sees them, in order, with their size in bits.
+const REGISTERS: [(&str, usize); 21] = [
+    ("v0", 8), ("v1", 8), ("v2", 8), ("v3", 8), ("v4", 8), ("v5", 8), ("v6", 8), ("v7", 8),
+    ("v8", 8), ("v9", 8), ("va", 8), ("vb", 8), ("vc", 8), ("vd", 8), ("ve", 8), ("vf", 8),
+    ("i", 16), ("pc", 16), ("sp", 8), ("dt", 8), ("st", 8)
+];
+
+// How often to look for an interrupt from GDB while running.
+const INTERRUPT_CHECK_CYCLES: u32 = 1000;
+
+// What to do after a packet from GDB.
+#[derive(Debug, PartialEq)]
+pub enum Action {
+    Reply(String),
+    Continue,
+    Step,
+    Detach,
+    Kill
+}
+
+// Wraps data in a packet: $data#checksum.
+pub fn packet(data: &str) -> String {
+    let checksum = data.bytes().fold(0u8, |sum, byte| sum.wrapping_add(byte));
+    format!("${}#{:02x}", data, checksum)
+}
+
+fn target_xml() -> String {
+    let mut xml = String::from("<?xml version=\"1.0\"?><!DOCTYPE target SYSTEM \"gdb-target.dtd\">\
+                                <target><feature name=\"org.chip8.cpu\">");
+    for (number, &(name, bits)) in REGISTERS.iter().enumerate() {
+        let kind = match name {
+            "pc" => "code_ptr",
+            "i" => "data_ptr",
+            _ if bits == 16 => "uint16",
+            _ => "uint8"
+        };
+        xml.push_str(&format!("<reg name=\"{}\" bitsize=\"{}\" type=\"{}\" regnum=\"{}\"/>", name, bits, kind, number));
+    }
+    xml.push_str("</feature></target>");
+    xml
+}
+
+fn register_value(state: &Chip8State, number: usize) -> u16 {
+    match number {
+        0..=15 => u16::from(state.v[number]),
+        16 => state.index,
+        17 => state.pc as u16,
+        18 => state.stack_pointer as u16,
+        19 => state.delay_timer,
+        _ => state.sound_timer
+    }
+}
+
+fn set_register(state: &mut Chip8State, number: usize, value: u16) {
+    match number {
+        0..=15 => state.v[number] = value as u8,
+        16 => state.index = value & 0xFFF,
+        17 => state.pc = (value & 0xFFF) as usize,
+        18 => state.stack_pointer = (value & 0xF) as usize,
+        19 => state.delay_timer = value & 0xFF,
+        _ => state.sound_timer = value & 0xFF
+    }
+}
+
+// Registers are sent as little endian hex.
+fn encode_register(state: &Chip8State, number: usize) -> String {
+    let value = register_value(state, number);
+    if REGISTERS[number].1 == 16 {
+        format!("{:02x}{:02x}", value & 0xFF, value >> 8)
+    } else {
+        format!("{:02x}", value)
+    }
+}
+
+fn decode_hex(text: &str) -> Option<Vec<u8>> {
+    if !text.is_ascii() || text.len() & 1 != 0 {
+        return None;
+    }
+    (0..text.len()).step_by(2).map(|i| u8::from_str_radix(&text[i..i + 2], 16).ok()).collect()
+}
+
+// "addr,length" as hex numbers.
+fn parse_range(text: &str) -> Option<(usize, usize)> {
+    let mut parts = text.splitn(2, ',');
+    let address = usize::from_str_radix(parts.next()?, 16).ok()?;
+    let length = usize::from_str_radix(parts.next()?, 16).ok()?;
+    Some((address, length))
+}
+
+fn read_registers(state: &Chip8State) -> String {
+    (0..REGISTERS.len()).map(|number| encode_register(state, number)).collect()
+}
+
+fn write_registers(state: &mut Chip8State, hex: &str) -> Option<()> {
+    let bytes = decode_hex(hex)?;
+    let mut at = 0;
+    for (number, &(_, bits)) in REGISTERS.iter().enumerate() {
+        let size = bits / 8;
+        if at + size > bytes.len() {
+            return None;
+        }
+        let value = if size == 2 { u16::from(bytes[at]) | u16::from(bytes[at + 1]) << 8 } else { u16::from(bytes[at]) };
+        set_register(state, number, value);
+        at += size;
+    }
+    Some(())
+}
+
+fn xfer_features(request: &str) -> String {
+    let (annex, range) = match request.find(':') {
+        Some(colon) => (&request[..colon], &request[colon + 1..]),
+        None => return "E01".to_string()
+    };
+    if annex != "target.xml" {
+        return "E00".to_string();
+    }
+    let xml = target_xml();
+    match parse_range(range) {
+        Some((offset, _)) if offset >= xml.len() => "l".to_string(),
+        Some((offset, length)) => {
+            let end = offset.saturating_add(length).min(xml.len());
+            let kind = if end == xml.len() { 'l' } else { 'm' };
+            format!("{}{}", kind, &xml[offset..end])
+        }
+        None => "E01".to_string()
+    }
+}
+
+fn encode_hex(text: &str) -> String {
+    text.bytes().map(|byte| format!("{:02x}", byte)).collect()
+}
+
+// GDB only knows addresses, so labels are reached through "monitor"
+// commands: "monitor break draw_player", "monitor delete draw_player+4" and
+// "monitor where". The reply is hex encoded text for GDB to print.
+fn monitor_command(command: &str, breakpoints: &mut Vec<usize>, state: &Chip8State, debug_info: &DebugInfo) -> String {
+    let mut words = command.split_whitespace();
+    let output = match (words.next(), words.next()) {
+        (Some(verb), Some(target)) if verb == "break" || verb == "delete" => {
+            match debug_info.resolve(target) {
+                Some(address) if verb == "break" => {
+                    if !breakpoints.contains(&address) {
+                        breakpoints.push(address);
+                    }
+                    format!("Breakpoint at 0x{:03X} ({})\n", address, debug_info.describe(address))
+                }
+                Some(address) => {
+                    breakpoints.retain(|&breakpoint| breakpoint != address);
+                    format!("Deleted breakpoint at 0x{:03X}\n", address)
+                }
+                None => format!("No label '{}'\n", target)
+            }
+        }
+        (Some("where"), None) => format!("PC 0x{:03X} {}\n", state.pc, debug_info.describe(state.pc)),
+        _ => "Commands: break <label>, delete <label>, where\n".to_string()
+    };
+    encode_hex(&output)
+}
+
+// Handles one packet while the CPU is stopped. Breakpoints are PCs at which
+// to stop before running the instruction there.
+pub fn handle_packet(data: &str, breakpoints: &mut Vec<usize>, state: &mut Chip8State, memory: &mut [u8],
+                     debug_info: &DebugInfo) -> Action {
+    let reply = |text: &str| Action::Reply(text.to_string());
+    let command = match data.chars().next() {
+        Some(command) => command,
+        None => return reply("")
+    };
+    let args = &data[1..];
+    match command {
+        '?' => reply("S05"),
+        'g' => Action::Reply(read_registers(state)),
+        'G' => {
+            match write_registers(state, args) {
+                Some(()) => reply("OK"),
+                None => reply("E01")
+            }
+        }
+        'p' => {
+            match usize::from_str_radix(args, 16) {
+                Ok(number) if number < REGISTERS.len() => Action::Reply(encode_register(state, number)),
+                _ => reply("E01")
+            }
+        }
+        'P' => {
+            let mut parts = args.splitn(2, '=');
+            let number = parts.next().and_then(|text| usize::from_str_radix(text, 16).ok());
+            let bytes = parts.next().and_then(decode_hex);
+            match (number, bytes) {
+                (Some(number), Some(bytes)) if number < REGISTERS.len() && !bytes.is_empty() => {
+                    let value = bytes.iter().rev().fold(0u16, |value, &byte| value << 8 | u16::from(byte));
+                    set_register(state, number, value);
+                    reply("OK")
+                }
+                _ => reply("E01")
+            }
+        }
+        'm' => {
+            match parse_range(args) {
+                Some((address, length)) if address.checked_add(length).is_some_and(|end| end <= memory.len()) => {
+                    Action::Reply(memory[address..address + length].iter().map(|byte| format!("{:02x}", byte)).collect())
+                }
+                _ => reply("E01")
+            }
+        }
+        'M' => {
+            let mut parts = args.splitn(2, ':');
+            let range = parts.next().and_then(parse_range);
+            let bytes = parts.next().and_then(decode_hex);
+            match (range, bytes) {
+                (Some((address, length)), Some(bytes)) if bytes.len() == length &&
+                    address.checked_add(length).is_some_and(|end| end <= memory.len()) => {
+                    memory[address..address + length].copy_from_slice(&bytes);
+                    reply("OK")
+                }
+                _ => reply("E01")
+            }
+        }
+        'Z' | 'z' => {
+            // Software and hardware breakpoints are the same thing here.
+            let fields: Vec<&str> = args.split(',').collect();
+            if fields.len() < 2 || (fields[0] != "0" && fields[0] != "1") {
+                return reply("");
+            }
+            let address = match usize::from_str_radix(fields[1], 16) {
+                Ok(address) => address,
+                Err(_) => return reply("E01")
+            };
+            if command == 'Z' {
+                if !breakpoints.contains(&address) {
+                    breakpoints.push(address);
+                }
+            } else {
+                breakpoints.retain(|&breakpoint| breakpoint != address);
+            }
+            reply("OK")
+        }
+        'c' | 's' => {
+            if let Ok(address) = usize::from_str_radix(args, 16) {
+                state.pc = address & 0xFFF;
+            }
+            if command == 'c' { Action::Continue } else { Action::Step }
+        }
+        'D' => Action::Detach,
+        'k' => Action::Kill,
+        'H' => reply("OK"),
+        'q' => {
+            if args.starts_with("Supported") {
+                reply("PacketSize=4000;qXfer:features:read+")
+            } else if args.starts_with("Xfer:features:read:") {
+                Action::Reply(xfer_features(&args["Xfer:features:read:".len()..]))
+            } else if args.starts_with("Rcmd,") {
+                match decode_hex(&args["Rcmd,".len()..]) {
+                    Some(bytes) => Action::Reply(monitor_command(&String::from_utf8_lossy(&bytes), breakpoints, state, debug_info)),
+                    None => reply("E01")
+                }
+            } else if args == "Attached" {
+                reply("1")
+            } else if args == "C" {
+                reply("QC1")
+            } else if args == "fThreadInfo" {
+                reply("m1")
+            } else if args == "sThreadInfo" {
+                reply("l")
+            } else {
+                reply("")
+            }
+        }
+        _ => reply("")
+    }
+}
+
+// A GDB remote serial protocol server, run as a monitor so that the
+// frontends carry on as usual. GDB connects before the first instruction,
+// with the CPU stopped.
+pub struct GdbStub {
+    stream: Option<TcpStream>,
+    debug_info: DebugInfo,
+    breakpoints: Vec<usize>,
+    stepping: bool,
+    // Don't stop at a breakpoint on the instruction execution resumes from.
+    resume_pc: Option<usize>,
+    cycles: u32,
+    // Stopped and GDB has been told so.
+    reported: bool
+}
+
+impl GdbStub {
+    pub fn listen(port: u16, debug_info: DebugInfo) -> Result<GdbStub, String> {
+        let listener = match TcpListener::bind(("127.0.0.1", port)) {
+            Ok(listener) => listener,
+            Err(e) => return Err(format!("Could not listen for GDB on port {}: {}", port, e))
+        };
+        eprintln!("Waiting for GDB on port {} (target remote localhost:{})", port, port);
+        match listener.accept() {
+            Ok((stream, _)) => {
+                let _ = stream.set_nodelay(true);
+                Ok(GdbStub { stream: Some(stream), debug_info, breakpoints: Vec::new(), stepping: true,
+                             resume_pc: None, cycles: 0, reported: true })
+            }
+            Err(e) => Err(format!("Could not accept GDB connection: {}", e))
+        }
+    }
+
+    fn send(&mut self, data: &str) {
+        let ok = match self.stream {
+            Some(ref mut stream) => stream.write_all(packet(data).as_bytes()).is_ok(),
+            None => return
+        };
+        if !ok {
+            self.stream = None;
+        }
+    }
+
+    fn read_byte(&mut self) -> Option<u8> {
+        let mut byte = [0u8];
+        let read = match self.stream {
+            Some(ref mut stream) => stream.read(&mut byte),
+            None => return None
+        };
+        match read {
+            Ok(1) => Some(byte[0]),
+            _ => {
+                self.stream = None;
+                None
+            }
+        }
+    }
+
+    // Reads the next packet's data, acknowledging it. Interrupts and acks
+    // are skipped.
+    fn read_packet(&mut self) -> Option<String> {
+        loop {
+            if self.read_byte()? != b'$' {
+                continue;
+            }
+            let mut data = Vec::new();
+            loop {
+                match self.read_byte()? {
+                    b'#' => break,
+                    byte => data.push(byte)
+                }
+            }
+            let checksum = [self.read_byte()?, self.read_byte()?];
+            let expected = data.iter().fold(0u8, |sum, &byte| sum.wrapping_add(byte));
+            let valid = ::std::str::from_utf8(&checksum).ok().and_then(|text| u8::from_str_radix(text, 16).ok()) == Some(expected);
+            let ack: &[u8] = if valid { b"+" } else { b"-" };
+            if let Some(ref mut stream) = self.stream {
+                let _ = stream.write_all(ack);
+            }
+            if valid {
+                return Some(String::from_utf8_lossy(&data).into_owned());
+            }
+        }
+    }
+
+    // GDB sends 0x03 to stop a running target. Nothing else is expected
+    // while running, so anything else waiting, such as acks, is dropped.
+    fn interrupted(&mut self) -> bool {
+        let mut bytes = [0u8; 64];
+        let stream = match self.stream {
+            Some(ref mut stream) => stream,
+            None => return false
+        };
+        let _ = stream.set_nonblocking(true);
+        let mut interrupted = false;
+        while let Ok(read) = stream.read(&mut bytes) {
+            if read == 0 {
+                break;
+            }
+            interrupted |= bytes[..read].contains(&0x03);
+        }
+        let _ = stream.set_nonblocking(false);
+        interrupted
+    }
+
+    // Talks to GDB until it resumes the CPU.
+    fn stopped(&mut self, state: &mut Chip8State, memory: &mut Vec<u8>) {
+        if !self.reported {
+            self.send("S05");
+            self.reported = true;
+        }
+        while let Some(data) = self.read_packet() {
+            match handle_packet(&data, &mut self.breakpoints, state, memory, &self.debug_info) {
+                Action::Reply(reply) => self.send(&reply),
+                Action::Continue => {
+                    self.stepping = false;
+                    break;
+                }
+                Action::Step => {
+                    self.stepping = true;
+                    break;
+                }
+                Action::Detach => {
+                    self.send("OK");
+                    self.stream = None;
+                }
+                Action::Kill => ::std::process::exit(0)
+            }
+        }
+        self.reported = false;
+        self.resume_pc = Some(state.pc);
+    }
+}
+
+impl Monitor for GdbStub {
+    fn before(&mut self, state: &mut Chip8State, memory: &mut Vec<u8>) {
+        if self.stream.is_none() {
+            return;
+        }
+        let resuming = self.resume_pc.take() == Some(state.pc);
+        let at_breakpoint = !resuming && self.breakpoints.contains(&state.pc);
+        self.cycles += 1;
+        let interrupted = self.cycles % INTERRUPT_CHECK_CYCLES == 0 && self.interrupted();
+        if self.stepping || at_breakpoint || interrupted {
+            self.stopped(state, memory);
+        }
+    }
+}
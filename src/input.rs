@@ -0,0 +1,221 @@
+use std::fs::File;
+use std::io::prelude::*;
+
+use gilrs::{Axis, Button, Gilrs};
+use glium::glutin;
+
+use keymap;
+use keymap::{Keyboard, Keymap};
+use movie::key_mask;
+
+// Anything that can hold down CHIP-8 keys. Every frame each source is
+// polled for a mask with bit n set for CHIP-8 key n, and the masks of all
+// sources are merged.
+pub trait InputSource {
+    // Window events are offered to every source before polling.
+    fn handle_event(&mut self, _event: &glutin::Event) {}
+
+    fn poll(&mut self, frame: u64) -> u16;
+}
+
+#[derive(Default)]
+pub struct Inputs {
+    sources: Vec<Box<dyn InputSource>>
+}
+
+impl Inputs {
+    pub fn add(&mut self, source: Box<dyn InputSource>) {
+        self.sources.push(source);
+    }
+
+    pub fn handle_event(&mut self, event: &glutin::Event) {
+        for source in self.sources.iter_mut() {
+            source.handle_event(event);
+        }
+    }
+
+    pub fn poll(&mut self, frame: u64, key_press: &mut [u8]) {
+        let mut mask = 0;
+        for source in self.sources.iter_mut() {
+            mask |= source.poll(frame);
+        }
+        for (key, pressed) in key_press.iter_mut().enumerate() {
+            *pressed = ((mask >> key) & 1) as u8;
+        }
+    }
+}
+
+// The glutin window's keyboard.
+pub struct KeyboardSource {
+    keymap: Keymap,
+    keyboard: Keyboard,
+    key_press: [u8; 16]
+}
+
+impl KeyboardSource {
+    pub fn new(keymap: Keymap) -> KeyboardSource {
+        KeyboardSource { keymap, keyboard: Default::default(), key_press: [0; 16] }
+    }
+}
+
+impl InputSource for KeyboardSource {
+    fn handle_event(&mut self, event: &glutin::Event) {
+        match *event {
+            glutin::Event::KeyboardInput(glutin::ElementState::Pressed, _, Some(key)) => {
+                self.keyboard.press(&self.keymap, &keymap::virtual_key_name(key), &mut self.key_press);
+            }
+            glutin::Event::KeyboardInput(glutin::ElementState::Released, _, Some(key)) => {
+                self.keyboard.release(&self.keymap, &keymap::virtual_key_name(key), &mut self.key_press);
+            }
+            _ => ()
+        }
+    }
+
+    fn poll(&mut self, _frame: u64) -> u16 {
+        key_mask(&self.key_press)
+    }
+}
+
+const GAMEPAD_BUTTONS: [Button; 19] = [
+    Button::South, Button::East, Button::North, Button::West, Button::C, Button::Z,
+    Button::LeftTrigger, Button::LeftTrigger2, Button::RightTrigger, Button::RightTrigger2,
+    Button::Select, Button::Start, Button::Mode, Button::LeftThumb, Button::RightThumb,
+    Button::DPadUp, Button::DPadDown, Button::DPadLeft, Button::DPadRight
+];
+
+// The names of every button and stick direction, as bound in the config
+// file.
+pub fn gamepad_names() -> Vec<String> {
+    let mut names: Vec<String> = GAMEPAD_BUTTONS.iter().map(|button| format!("{:?}", button)).collect();
+    for stick in ["LeftStick", "RightStick"].iter() {
+        for direction in ["Up", "Down", "Left", "Right"].iter() {
+            names.push(format!("{}{}", stick, direction));
+        }
+    }
+    names
+}
+
+// How far a stick has to move before it counts as a direction.
+const STICK_DEADZONE: f32 = 0.5;
+
+// Every connected gamepad. Buttons are named as in gilrs (South, DPadUp,
+// Start...) and the sticks as LeftStickUp, RightStickLeft and so on.
+pub struct GamepadSource {
+    gilrs: Gilrs,
+    keymap: Keymap
+}
+
+impl GamepadSource {
+    pub fn new(keymap: Keymap) -> Result<GamepadSource, String> {
+        match Gilrs::new() {
+            Ok(gilrs) => Ok(GamepadSource { gilrs, keymap }),
+            Err(e) => Err(format!("Gamepads are not available: {}", e))
+        }
+    }
+
+    fn press(&self, name: &str, mask: &mut u16) {
+        for key in self.keymap.keys_for(name) {
+            *mask |= 1 << key;
+        }
+    }
+}
+
+impl InputSource for GamepadSource {
+    fn poll(&mut self, _frame: u64) -> u16 {
+        // Gamepad state only updates as events are read.
+        while self.gilrs.next_event().is_some() {}
+
+        let mut mask = 0;
+        for (_, gamepad) in self.gilrs.gamepads() {
+            for &button in GAMEPAD_BUTTONS.iter() {
+                if gamepad.is_pressed(button) {
+                    self.press(&format!("{:?}", button), &mut mask);
+                }
+            }
+            for &(stick, x_axis, y_axis) in [("LeftStick", Axis::LeftStickX, Axis::LeftStickY),
+                                             ("RightStick", Axis::RightStickX, Axis::RightStickY)].iter() {
+                let x = gamepad.value(x_axis);
+                let y = gamepad.value(y_axis);
+                if x < -STICK_DEADZONE {
+                    self.press(&format!("{}Left", stick), &mut mask);
+                }
+                if x > STICK_DEADZONE {
+                    self.press(&format!("{}Right", stick), &mut mask);
+                }
+                if y > STICK_DEADZONE {
+                    self.press(&format!("{}Up", stick), &mut mask);
+                }
+                if y < -STICK_DEADZONE {
+                    self.press(&format!("{}Down", stick), &mut mask);
+                }
+            }
+        }
+        mask
+    }
+}
+
+// Keys held on given frames, read from a text file with one line per
+// change:
+//
+//   # frame keys
+//   0 -
+//   60 5        hold 5 from frame 60
+//   64 4 6      then 4 and 6 together
+//   70 -        then nothing
+#[derive(Debug, Default, PartialEq)]
+pub struct ScriptSource {
+    changes: Vec<(u64, u16)>
+}
+
+pub fn parse_script(text: &str) -> Result<ScriptSource, String> {
+    let mut script: ScriptSource = Default::default();
+    for (line_number, raw_line) in text.lines().enumerate() {
+        let line = match raw_line.find('#') {
+            Some(comment) => &raw_line[..comment],
+            None => raw_line
+        };
+        let mut fields = line.split_whitespace();
+        let frame = match fields.next() {
+            Some(frame) => frame,
+            None => continue
+        };
+        let error = || format!("Input script line {}: could not read '{}'", line_number + 1, raw_line);
+        let frame: u64 = frame.parse().map_err(|_| error())?;
+        let mut mask = 0;
+        for key in fields {
+            if key == "-" {
+                continue;
+            }
+            match u16::from_str_radix(key, 16) {
+                Ok(key) if key < 16 => mask |= 1 << key,
+                _ => return Err(error())
+            }
+        }
+        if let Some(&(last_frame, _)) = script.changes.last() {
+            if frame < last_frame {
+                return Err(format!("Input script line {}: frames should be in order", line_number + 1));
+            }
+        }
+        script.changes.push((frame, mask));
+    }
+    Ok(script)
+}
+
+pub fn load_script(path: &str) -> Result<ScriptSource, String> {
+    let mut text = String::new();
+    match File::open(path) {
+        Ok(mut f) => {
+            if let Err(e) = f.read_to_string(&mut text) {
+                return Err(format!("Could not read input script '{}': {}", path, e));
+            }
+        }
+        Err(e) => return Err(format!("Could not open input script '{}': {}", path, e))
+    }
+    parse_script(&text)
+}
+
+impl InputSource for ScriptSource {
+    fn poll(&mut self, frame: u64) -> u16 {
+        self.changes.iter().take_while(|&&(change_frame, _)| change_frame <= frame).last().map(|&(_, mask)| mask).unwrap_or(0)
+    }
+}
@@ -0,0 +1,207 @@
+use std::collections::HashSet;
+
+use glium::glutin::VirtualKeyCode;
+
+use config::{ConfigFile, ConfigValue};
+use input;
+
+// Host keys for CHIP-8 keys 0 to F, by layout. Key names are glutin's
+// VirtualKeyCode names, with the "Key" prefix dropped from the number row so
+// that they match the characters a terminal sends.
+const LAYOUTS: [(&str, [&str; 16]); 3] = [
+    // 1 2 3 4 / Q W E R / A S D F / Z X C V
+    ("qwerty", ["X", "1", "2", "3", "Q", "W", "E", "A", "S", "D", "Z", "C", "4", "R", "F", "V"]),
+    // 1 2 3 4 / A Z E R / Q S D F / W X C V
+    ("azerty", ["X", "1", "2", "3", "A", "Z", "E", "Q", "S", "D", "W", "C", "4", "R", "F", "V"]),
+    // The digits on the keypad are the same digits on the CHIP-8 keypad,
+    // A to F are / * - + Enter and the decimal point.
+    ("keypad", ["Numpad0", "Numpad1", "Numpad2", "Numpad3", "Numpad4", "Numpad5", "Numpad6", "Numpad7",
+                "Numpad8", "Numpad9", "Divide", "Multiply", "Subtract", "Add", "NumpadEnter", "Decimal"])
+];
+
+// Gamepad buttons for CHIP-8 keys: the directions go to 5 / 7 8 9, which
+// most games use as up / left down right, and the face buttons to the keys
+// around them.
+const GAMEPAD_BINDINGS: [(&str, usize); 12] = [
+    ("DPadUp", 0x5), ("DPadLeft", 0x7), ("DPadDown", 0x8), ("DPadRight", 0x9),
+    ("LeftStickUp", 0x5), ("LeftStickLeft", 0x7), ("LeftStickDown", 0x8), ("LeftStickRight", 0x9),
+    ("South", 0x6), ("East", 0x4), ("West", 0xA), ("North", 0xC)
+];
+
+// Every VirtualKeyCode name, so typos in the config file can be caught.
+const KEY_NAMES: [&str; 150] = [
+    "Key1", "Key2", "Key3", "Key4", "Key5", "Key6", "Key7", "Key8", "Key9", "Key0",
+    "A", "B", "C", "D", "E", "F", "G", "H", "I", "J", "K", "L", "M", "N", "O", "P", "Q", "R", "S",
+    "T", "U", "V", "W", "X", "Y", "Z",
+    "Escape", "F1", "F2", "F3", "F4", "F5", "F6", "F7", "F8", "F9", "F10", "F11", "F12", "F13",
+    "F14", "F15",
+    "Snapshot", "Scroll", "Pause", "Insert", "Home", "Delete", "End", "PageDown", "PageUp",
+    "Left", "Up", "Right", "Down", "Back", "Return", "Space", "Compose", "Numlock",
+    "Numpad0", "Numpad1", "Numpad2", "Numpad3", "Numpad4", "Numpad5", "Numpad6", "Numpad7",
+    "Numpad8", "Numpad9",
+    "AbntC1", "AbntC2", "Add", "Apostrophe", "Apps", "At", "Ax", "Backslash", "Calculator",
+    "Capital", "Colon", "Comma", "Convert", "Decimal", "Divide", "Equals", "Grave", "Kana", "Kanji",
+    "LAlt", "LBracket", "LControl", "LMenu", "LShift", "LWin", "Mail", "MediaSelect", "MediaStop",
+    "Minus", "Multiply", "Mute", "MyComputer", "NavigateForward", "NavigateBackward", "NextTrack",
+    "NoConvert", "NumpadComma", "NumpadEnter", "NumpadEquals", "OEM102", "Period", "PlayPause",
+    "Power", "PrevTrack", "RAlt", "RBracket", "RControl", "RMenu", "RShift", "RWin", "Semicolon",
+    "Slash", "Sleep", "Stop", "Subtract", "Sysrq", "Tab", "Underline", "Unlabeled", "VolumeDown",
+    "VolumeUp", "Wake", "WebBack", "WebFavorites", "WebForward", "WebHome", "WebRefresh",
+    "WebSearch", "WebStop", "Yen"
+];
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct Keymap {
+    // (normalized host key name, CHIP-8 key)
+    bindings: Vec<(String, usize)>
+}
+
+impl Default for Keymap {
+    fn default() -> Keymap {
+        layout("qwerty").unwrap()
+    }
+}
+
+pub fn layout(name: &str) -> Result<Keymap, String> {
+    for &(layout_name, keys) in LAYOUTS.iter() {
+        if layout_name == name.to_lowercase() {
+            let mut keymap = Keymap { bindings: Vec::new() };
+            for (chip8_key, host_key) in keys.iter().enumerate() {
+                keymap.bindings.push((normalize(host_key), chip8_key));
+            }
+            return Ok(keymap);
+        }
+    }
+    let names: Vec<&str> = LAYOUTS.iter().map(|&(layout_name, _)| layout_name).collect();
+    Err(format!("Unknown keyboard layout '{}', expected one of: {}", name, names.join(", ")))
+}
+
+pub fn default_gamepad() -> Keymap {
+    Keymap { bindings: GAMEPAD_BINDINGS.iter().map(|&(name, key)| (normalize(name), key)).collect() }
+}
+
+pub fn virtual_key_name(key: VirtualKeyCode) -> String {
+    normalize(&format!("{:?}", key))
+}
+
+// Lower case, and "Key1" is the same key as "1".
+fn normalize(name: &str) -> String {
+    let lower = name.to_lowercase();
+    if lower.len() == 4 && lower.starts_with("key") {
+        lower[3..].to_string()
+    } else {
+        lower
+    }
+}
+
+impl Keymap {
+    // Replaces every host key bound to a CHIP-8 key.
+    pub fn bind(&mut self, chip8_key: usize, host_keys: &[String]) {
+        self.bindings.retain(|&(_, key)| key != chip8_key);
+        for host_key in host_keys {
+            self.bindings.push((normalize(host_key), chip8_key));
+        }
+    }
+
+    pub fn keys_for(&self, host_key: &str) -> Vec<usize> {
+        let name = normalize(host_key);
+        self.bindings.iter().filter(|&&(ref bound, _)| *bound == name).map(|&(_, key)| key).collect()
+    }
+
+    pub fn host_keys(&self, chip8_key: usize) -> Vec<&str> {
+        self.bindings.iter().filter(|&&(_, key)| key == chip8_key).map(|&(ref name, _)| &name[..]).collect()
+    }
+}
+
+// Tracks which host keys are down, so that a CHIP-8 key bound to several
+// host keys stays pressed until the last of them is released.
+#[derive(Default)]
+pub struct Keyboard {
+    held: HashSet<String>
+}
+
+impl Keyboard {
+    pub fn press(&mut self, keymap: &Keymap, host_key: &str, key_press: &mut [u8]) {
+        self.held.insert(normalize(host_key));
+        for key in keymap.keys_for(host_key) {
+            key_press[key] = 1;
+        }
+    }
+
+    pub fn release(&mut self, keymap: &Keymap, host_key: &str, key_press: &mut [u8]) {
+        self.held.remove(&normalize(host_key));
+        for key in keymap.keys_for(host_key) {
+            if !keymap.host_keys(key).iter().any(|name| self.held.contains(*name)) {
+                key_press[key] = 0;
+            }
+        }
+    }
+}
+
+// Builds the keymap from a layout and then the bindings in the [keys]
+// section, then the layout and bindings in the [keys.<rom file name>]
+// section. A layout given on the command line replaces both layouts but
+// the bindings still apply.
+// The layout from the command line, else the ROM's own, else the default.
+pub fn layout_name(config: &ConfigFile, rom_name: &str, layout_flag: Option<&str>) -> Result<String, String> {
+    Ok(match layout_flag {
+        Some(name) => name.to_string(),
+        None => {
+            match config.get_str(&format!("keys.{}.layout", rom_name))? {
+                Some(name) => name.to_string(),
+                None => config.get_str("keys.layout")?.unwrap_or("qwerty").to_string()
+            }
+        }
+    })
+}
+
+pub fn from_config(config: &ConfigFile, rom_name: &str, layout_flag: Option<&str>) -> Result<Keymap, String> {
+    let rom_section = format!("keys.{}", rom_name);
+    let mut keymap = layout(&layout_name(config, rom_name, layout_flag)?)?;
+    apply_bindings(&mut keymap, config, "keys", &KEY_NAMES)?;
+    apply_bindings(&mut keymap, config, &rom_section, &KEY_NAMES)?;
+    Ok(keymap)
+}
+
+// The default gamepad bindings, with any changes from the [gamepad] section.
+pub fn gamepad_from_config(config: &ConfigFile) -> Result<Keymap, String> {
+    let mut keymap = default_gamepad();
+    let names = input::gamepad_names();
+    let known: Vec<&str> = names.iter().map(|name| &name[..]).collect();
+    apply_bindings(&mut keymap, config, "gamepad", &known)?;
+    Ok(keymap)
+}
+
+// Binds the keys in `section`, which have to be among `known` host keys.
+fn apply_bindings(keymap: &mut Keymap, config: &ConfigFile, section: &str, known: &[&str]) -> Result<(), String> {
+    for name in config.section_keys(section) {
+        if name == "layout" || name == "enabled" {
+            continue;
+        }
+        let chip8_key = match usize::from_str_radix(&name, 16) {
+            Ok(key) if key < 16 => key,
+            _ => return Err(format!("[{}] {} is not a CHIP-8 key, expected 0 to F", section, name))
+        };
+        let host_keys = match *config.get(&format!("{}.{}", section, name)).unwrap() {
+            ConfigValue::Str(ref host_key) => vec![host_key.clone()],
+            ConfigValue::List(ref values) => {
+                let mut host_keys = Vec::new();
+                for value in values {
+                    match *value {
+                        ConfigValue::Str(ref host_key) => host_keys.push(host_key.clone()),
+                        _ => return Err(format!("[{}] {} should be a list of key names", section, name))
+                    }
+                }
+                host_keys
+            }
+            _ => return Err(format!("[{}] {} should be a key name or a list of key names", section, name))
+        };
+        for host_key in &host_keys {
+            if !known.iter().any(|known_key| normalize(known_key) == normalize(host_key)) {
+                return Err(format!("[{}] {} is bound to unknown key '{}'", section, name, host_key));
+            }
+        }
+        keymap.bind(chip8_key, &host_keys);
+    }
+    Ok(())
+}
@@ -3,17 +3,50 @@ extern crate glium;
 extern crate schedule_recv;
 extern crate time;
 extern crate rand;
+extern crate gilrs;
 
 use std::io::prelude::*;
 
 use std::fs::File;
-use std::borrow::Cow;
 
 use rand::Rng;
 
-use glium::{DisplayBuild, Surface};
+use glium::DisplayBuild;
 use glium::glutin;
 
+mod analysis;
+mod bench;
+mod compiled;
+mod config;
+mod coverage;
+mod dap;
+mod debuginfo;
+mod decompile;
+mod disasm;
+mod engine;
+mod framebuffer;
+mod gdb;
+mod input;
+mod json;
+mod keymap;
+mod monitor;
+mod movie;
+mod palette;
+mod profile;
+mod quirks;
+mod recompile;
+mod render;
+mod trace;
+mod tui;
+
+use config::{Frontend, Options};
+use framebuffer::Framebuffer;
+use input::Inputs;
+use monitor::Monitors;
+use movie::MovieSession;
+use quirks::Quirks;
+use render::DisplayMode;
+
 #[cfg(test)]
 mod tests;
 
@@ -46,36 +79,87 @@ struct Chip8State {
     stack_pointer: usize,
     stack: Vec<usize>,
     v: Vec<u8>,
-    gfx: Vec<u8>,
-    key_press: Vec<u8>
+    gfx: Framebuffer,
+    key_press: Vec<u8>,
+    quirks: Quirks,
+    // Set while FX0A is blocked, along with the key once it has gone down.
+    waiting_for_key: bool,
+    wait_key_pressed: Option<usize>,
+    // Xorshift state for CXNN, seeded so that runs can be replayed.
+    rng: u32,
+    // Instructions run so far.
+    cycles: u64
+}
+
+// Runs the subcommand called `name` instead of the emulator, giving the
+// exit code, or None if there is no such subcommand.
+fn run_subcommand(name: &str, args: &[String]) -> Option<Result<i32, String>> {
+    let result = match name {
+        "diff-traces" => trace::diff_command(args).map(|differ| if differ { 1 } else { 0 }),
+        "analyze" => analysis::analyze_command(args).map(|_| 0),
+        "decompile" => decompile::decompile_command(args).map(|_| 0),
+        "recompile" => recompile::recompile_command(args).map(|_| 0),
+        "bench" => bench::bench_command(args).map(|_| 0),
+        "profile-report" => profile::report_command(args).map(|_| 0),
+        _ => return None
+    };
+    Some(result)
 }
 
 fn main() {
+    let args: Vec<String> = std::env::args().skip(1).collect();
+    if let Some(name) = args.first() {
+        match run_subcommand(name, &args[1..]) {
+            Some(Ok(code)) => std::process::exit(code),
+            Some(Err(e)) => {
+                eprintln!("{}", e);
+                // diff-traces already uses 1 for traces that differ.
+                std::process::exit(if name == "diff-traces" { 2 } else { 1 });
+            }
+            None => ()
+        }
+    }
+    let mut options = match config::load_options(&args) {
+        Ok(options) => options,
+        Err(e) => {
+            eprintln!("{}", e);
+            std::process::exit(1);
+        }
+    };
+
+    // Load program, or run the one compiled in unless given another.
+    let mut file_data = Vec::new();
+    if !compiled::ROM.is_empty() && options.rom_path == config::DEFAULT_ROM_PATH {
+        file_data.extend_from_slice(compiled::ROM);
+    } else {
+        let mut f = File::open(&options.rom_path).unwrap();
+        f.read_to_end(&mut file_data).unwrap();
+    }
+    let file_size = file_data.len();
+    
+    if options.seed.is_none() {
+        options.seed = Some(rand::thread_rng().gen::<u32>());
+    }
+    let mut session = match movie::start_session(&mut options, &file_data) {
+        Ok(session) => session,
+        Err(e) => {
+            eprintln!("{}", e);
+            std::process::exit(1);
+        }
+    };
+    
     // Initialize State
     let mut state: Chip8State = Default::default(); 
     let mut memory = vec![0u8; 4096];
     init_state(&mut state);
+    state.quirks = options.quirks;
+    state.rng = options.seed.unwrap();
     
     // Load fontset
     for x in 0..80 {
         memory[x] = CHIP8_FONTSET[x];
     }
     
-    // Open window
-    let display = glutin::WindowBuilder::new()
-        .build_glium()
-        .unwrap();
-        
-    let mut target = display.draw();
-    target.clear_color(0.0, 0.0, 1.0, 1.0);
-    target.finish().unwrap();
-    
-    // Load program    
-    let mut file_data = Vec::new();
-    let mut f = File::open("c:\\emu\\chip8\\BRIX.").unwrap();
-    
-    let file_size = f.read_to_end(&mut file_data).unwrap();
-    
     for x in 0..file_size {
         memory[0x200 + x] = file_data[x];
     }
@@ -100,12 +184,199 @@ fn main() {
     memory[0x314] = 0x12;
     memory[0x315] = 0x00;*/
     
+    // The frontends add their own keyboards.
+    let mut inputs: Inputs = Default::default();
+    if options.use_gamepad && options.frontend != Frontend::Headless {
+        match input::GamepadSource::new(options.gamepad_keymap.clone()) {
+            Ok(gamepad) => inputs.add(Box::new(gamepad)),
+            Err(e) => eprintln!("{}", e)
+        }
+    }
+    if let Some(ref path) = options.input_script {
+        match input::load_script(path) {
+            Ok(script) => inputs.add(Box::new(script)),
+            Err(e) => {
+                eprintln!("{}", e);
+                std::process::exit(1);
+            }
+        }
+    }
+    
+    let debug_info = match options.debug_info_path {
+        Some(ref path) => match debuginfo::load_debug_info(path) {
+            Ok(debug_info) => debug_info,
+            Err(e) => {
+                eprintln!("{}", e);
+                std::process::exit(1);
+            }
+        },
+        None => Default::default()
+    };
+    let mut monitors: Monitors = Default::default();
+    monitors.set_engine(options.engine);
+    if let Some(ref path) = options.trace_path {
+        match trace::Tracer::create(path, options.trace_format, options.trace_filter.clone(), debug_info.clone()) {
+            Ok(tracer) => monitors.add(Box::new(tracer)),
+            Err(e) => {
+                eprintln!("{}", e);
+                std::process::exit(1);
+            }
+        }
+    }
+    if let Some(ref path) = options.profile_path {
+        match profile::Profiler::create(path, debug_info.clone()) {
+            Ok(profiler) => monitors.add(Box::new(profiler)),
+            Err(e) => {
+                eprintln!("{}", e);
+                std::process::exit(1);
+            }
+        }
+    }
+    if options.coverage_path.is_some() || options.heatmap_path.is_some() {
+        match coverage::Coverage::create(options.coverage_path.clone(), options.heatmap_path.clone(),
+                                         &file_data, debug_info.clone()) {
+            Ok(coverage) => monitors.add(Box::new(coverage)),
+            Err(e) => {
+                eprintln!("{}", e);
+                std::process::exit(1);
+            }
+        }
+    }
+    if let Some(ref transport) = options.dap {
+        match dap::DapServer::start(transport, debug_info.clone()) {
+            Ok(server) => monitors.add(Box::new(server)),
+            Err(e) => {
+                eprintln!("{}", e);
+                std::process::exit(1);
+            }
+        }
+    }
+    if let Some(port) = options.gdb_port {
+        match gdb::GdbStub::listen(port, debug_info.clone()) {
+            Ok(stub) => monitors.add(Box::new(stub)),
+            Err(e) => {
+                eprintln!("{}", e);
+                std::process::exit(1);
+            }
+        }
+    }
+    
+    match options.frontend {
+        Frontend::Window => run_window(&options, &mut state, &mut memory, &mut inputs, &mut monitors, &mut session),
+        Frontend::Terminal => tui::run(&options, &mut state, &mut memory, &mut inputs, &mut monitors, &mut session),
+        Frontend::Headless => run_headless(&options, &mut state, &mut memory, &mut inputs, &mut monitors, &mut session)
+    }
+}
+
+fn run_headless(options: &Options, state: &mut Chip8State, memory: &mut Vec<u8>, inputs: &mut Inputs,
+                monitors: &mut Monitors, session: &mut MovieSession) {
+    // Under a debugger, run until it kills the emulator.
+    let forever = (options.gdb_port.is_some() || options.dap.is_some()) && options.frames == 0;
+    if options.frames == 0 && !session.is_playing() && !forever {
+        eprintln!("The headless frontend needs --frames, --play, --gdb or --dap");
+        std::process::exit(1);
+    }
+    loop {
+        if options.frames > 0 && session.frame() >= options.frames {
+            break;
+        }
+        if options.frames == 0 && !session.is_playing() && !forever {
+            break;
+        }
+        inputs.poll(session.frame(), &mut state.key_press);
+        session.begin_frame(&mut state.key_press);
+        monitors.run(state, memory, options.cycles_per_frame, |_| ());
+        update_timers(state);
+        monitors.end_frame();
+        if let Err(e) = session.end_frame(&state.gfx) {
+            eprintln!("{}", e);
+            std::process::exit(1);
+        }
+    }
+    let summary = format!("Ran {} frames, framebuffer hash {:016x}", session.frame(), state.gfx.hash());
+    // Stdout belongs to the debug adapter's messages.
+    if options.dap.as_ref().is_some_and(|dap| dap == "stdio") {
+        eprintln!("{}", summary);
+    } else {
+        println!("{}", summary);
+    }
+}
+
+fn run_window(options: &Options, state: &mut Chip8State, memory: &mut Vec<u8>, inputs: &mut Inputs,
+              monitors: &mut Monitors, session: &mut MovieSession) {
+    // Open window
+    let display = glutin::WindowBuilder::new()
+        .build_glium()
+        .unwrap();
+        
+    let border_color = options.border_color.unwrap_or(options.palette.colors[0]);
+    let mut renderer = render::Renderer::new(&display, &options.palette, options.border, border_color);
+    renderer.redraw(&display);
+    
     // Emulation loop
     // 60 Hz
     let tick = schedule_recv::periodic_ms(1000 / 60);
+    let mut phosphor = render::Phosphor::new(options.phosphor_decay);
+    inputs.add(Box::new(input::KeyboardSource::new(options.keymap.clone())));
+    let mut waiting_for_key = false;
+    // Whether the screen was drawn since it was last presented.
+    let mut drawn = false;
     loop {
-        handle_keyboard(&mut state, &display);
-        emulate_cycle(&mut state, &mut memory, &display);
+        for ev in display.poll_events() {
+            match ev {
+                // Return rather than exit, so monitors write their files.
+                glutin::Event::Closed => return,
+                glutin::Event::Resized(..) => renderer.redraw(&display),
+                _ => ()
+            }
+            inputs.handle_event(&ev);
+        }
+        inputs.poll(session.frame(), &mut state.key_press);
+        session.begin_frame(&mut state.key_press);
+        monitors.run(state, memory, options.cycles_per_frame, |state| {
+            drawn = true;
+            match options.display_mode {
+                DisplayMode::Immediate => {
+                    if !renderer.presented_this_frame() {
+                        renderer.present(&display, &render::frame_image(&state.gfx, &options.palette));
+                    }
+                }
+                DisplayMode::Phosphor => phosphor.expose(&state.gfx),
+                DisplayMode::Vblank => ()
+            }
+        });
+        update_timers(state);
+        monitors.end_frame();
+        if let Err(e) = session.end_frame(&state.gfx) {
+            eprintln!("{}", e);
+            std::process::exit(1);
+        }
+        
+        if state.waiting_for_key != waiting_for_key {
+            waiting_for_key = state.waiting_for_key;
+            let title = if waiting_for_key { "rust_chip8 - waiting for key" } else { "rust_chip8" };
+            if let Some(window) = display.get_window() {
+                window.set_title(title);
+            }
+        }
+        
+        // Vertical blank
+        match options.display_mode {
+            DisplayMode::Immediate | DisplayMode::Vblank => {
+                if drawn && !renderer.presented_this_frame() {
+                    renderer.present(&display, &render::frame_image(&state.gfx, &options.palette));
+                    drawn = false;
+                }
+            }
+            DisplayMode::Phosphor => {
+                if phosphor.is_lit() {
+                    phosphor.expose(&state.gfx);
+                    renderer.present(&display, &phosphor.image(&options.palette));
+                    phosphor.fade();
+                }
+            }
+        }
+        renderer.vblank();
         tick.recv().unwrap();
     }
     //println!("Opcode {}", opcode);
@@ -116,162 +387,23 @@ fn init_state(state: &mut Chip8State)
     state.pc = 0x200;
     state.stack = vec![0usize; 16];
     state.v = vec![0u8; 16];
-    state.gfx = vec![0u8; 2048];
+    state.gfx = Default::default();
     state.key_press = vec![0u8; 16];
 }
 
-fn handle_keyboard(state: &mut Chip8State, display: &glium::backend::glutin_backend::GlutinFacade) {
-    for ev in display.poll_events() {
-        match ev {
-            glium::glutin::Event::KeyboardInput(glium::glutin::ElementState::Pressed, _, Some(key)) => {
-                match key {
-                    glium::glutin::VirtualKeyCode::Key1 => {
-                       state.key_press[0x1] = 1;
-                    }
-                    glium::glutin::VirtualKeyCode::Key2 => {
-                       state.key_press[0x2] = 1;
-                    }
-                    glium::glutin::VirtualKeyCode::Key3 => {
-                       state.key_press[0x3] = 1;
-                    }
-                    glium::glutin::VirtualKeyCode::Key4 => {
-                       state.key_press[0xC] = 1;
-                    }
-                    glium::glutin::VirtualKeyCode::Q => {
-                       state.key_press[0x4] = 1;
-                    }
-                    glium::glutin::VirtualKeyCode::W => {
-                       state.key_press[0x5] = 1;
-                    }
-                    glium::glutin::VirtualKeyCode::E => {
-                       state.key_press[0x6] = 1;
-                    }
-                    glium::glutin::VirtualKeyCode::R => {
-                       state.key_press[0xD] = 1;
-                    }
-                    glium::glutin::VirtualKeyCode::A => {
-                       state.key_press[0x7] = 1;
-                    }
-                    glium::glutin::VirtualKeyCode::S => {
-                       state.key_press[0x8] = 1;
-                    }
-                    glium::glutin::VirtualKeyCode::D => {
-                       state.key_press[0x9] = 1;
-                    }
-                    glium::glutin::VirtualKeyCode::F => {
-                       state.key_press[0xE] = 1;
-                    }
-                    glium::glutin::VirtualKeyCode::Z => {
-                       state.key_press[0xA] = 1;
-                    }
-                    glium::glutin::VirtualKeyCode::X => {
-                       state.key_press[0x0] = 1;
-                    }
-                    glium::glutin::VirtualKeyCode::C => {
-                       state.key_press[0xB] = 1;
-                    }
-                    glium::glutin::VirtualKeyCode::V => {
-                       state.key_press[0xF] = 1;
-                    }
-                    _ => ()
-            }
-        }
-            glium::glutin::Event::KeyboardInput(glium::glutin::ElementState::Released, _, Some(key)) => {
-                match key {
-                    glium::glutin::VirtualKeyCode::Key1 => {
-                        state.key_press[0x1] = 0;
-                    }
-                    glium::glutin::VirtualKeyCode::Key2 => {
-                       state.key_press[0x2] = 0;
-                    }
-                    glium::glutin::VirtualKeyCode::Key3 => {
-                       state.key_press[0x3] = 0;
-                    }
-                    glium::glutin::VirtualKeyCode::Key4 => {
-                       state.key_press[0xC] = 0;
-                    }
-                    glium::glutin::VirtualKeyCode::Q => {
-                       state.key_press[0x4] = 0;
-                    }
-                    glium::glutin::VirtualKeyCode::W => {
-                       state.key_press[0x5] = 0;
-                    }
-                    glium::glutin::VirtualKeyCode::E => {
-                       state.key_press[0x6] = 0;
-                    }
-                    glium::glutin::VirtualKeyCode::R => {
-                       state.key_press[0xD] = 0;
-                    }
-                    glium::glutin::VirtualKeyCode::A => {
-                       state.key_press[0x7] = 0;
-                    }
-                    glium::glutin::VirtualKeyCode::S => {
-                       state.key_press[0x8] = 0;
-                    }
-                    glium::glutin::VirtualKeyCode::D => {
-                       state.key_press[0x9] = 0;
-                    }
-                    glium::glutin::VirtualKeyCode::F => {
-                       state.key_press[0xE] = 0;
-                    }
-                    glium::glutin::VirtualKeyCode::Z => {
-                       state.key_press[0xA] = 0;
-                    }
-                    glium::glutin::VirtualKeyCode::X => {
-                       state.key_press[0x0] = 0;
-                    }
-                    glium::glutin::VirtualKeyCode::C => {
-                       state.key_press[0xB] = 0;
-                    }
-                    glium::glutin::VirtualKeyCode::V => {
-                       state.key_press[0xF] = 0;
-                    }
-                    _ => ()
-                }
-            }
-             _ => ()
-        }
-    }
-    //println!("{:?}", state.key_press);
-}
-
-fn emulate_cycle(state: &mut Chip8State, memory: &mut Vec<u8>, display: &glium::backend::glutin_backend::GlutinFacade) {   
+fn emulate_cycle(state: &mut Chip8State, memory: &mut Vec<u8>) -> bool {   
     let opcode = get_opcode(state.pc, &memory);
     //println!("Opcode: {:X}", opcode);
+    state.cycles += 1;
     
-    let draw_flag = execute_opcode(opcode, state, memory);
-    if draw_flag == true {
-        //println!("Redraw Required");
-        let mut image_data = vec![0u8; 2048];
-        for y in 0..32 {
-            for x in 0..64 {
-                if state.gfx[y * 64 + x] == 1 {
-                    image_data[(31 - y) * 64 + x] = u8::max_value();
-                } else {
-                    image_data[(31 - y) * 64 + x] = 0;
-                }
-            }
-        }
-        let target = display.draw();
-        let screen = glium::texture::RawImage2d {
-                      data: Cow::Borrowed(&image_data),
-                      width: 64,
-                      height: 32,
-                      format: glium::texture::ClientFormat::U3U3U2};
-        let opengl_texture = glium::Texture2d::new(display, screen).unwrap();
-        
-        opengl_texture.as_surface().fill(&target, glium::uniforms::MagnifySamplerFilter::Nearest);
-        
-        target.finish().unwrap();
-    }
-    update_timers(state);
+    execute_opcode(opcode, state, memory)
     
     //println!("{:?}", state);
     //let mut line = String::new();
     // let _ = io::stdin().read_line(&mut line);
 }
 
-fn get_opcode(pc: usize, memory: &Vec<u8>) -> u16 {
+fn get_opcode(pc: usize, memory: &[u8]) -> u16 {
     // Shift upper bits over 8 bits, then OR with lower 8 bits.
     u16::from(memory[pc]) << 8 | u16::from(memory[pc + 1])
 }
@@ -283,16 +415,14 @@ fn execute_opcode(opcode: u16, state: &mut Chip8State, memory: &mut Vec<u8>) ->
         0x0000 => {
             if opcode == 0x00E0 {
                 //println!("0x00E0 opcode (cls)");
-                for x in 0..2048 {
-                    state.gfx[x] = 0;
-                }
+                state.gfx.clear();
                 draw_flag = true;
             } else if opcode == 0x00EE {
                 //println!("0x00EE opcode (return)");
                 state.stack_pointer -= 1;
                 state.pc = state.stack[state.stack_pointer];
             } else {
-                println!("Unknown opcode: {:#X}", decode);
+                eprintln!("Unknown opcode: {:#X}", decode);
             }
             state.pc += 2;
         }
@@ -384,7 +514,7 @@ fn execute_opcode(opcode: u16, state: &mut Chip8State, memory: &mut Vec<u8>) ->
                     }
                 }
                 _ => {
-                    println!("Unknown opcode: {:#X}", opcode);
+                    eprintln!("Unknown opcode: {:#X}", opcode);
                     }
                 }
                 state.pc +=2;
@@ -402,29 +532,21 @@ fn execute_opcode(opcode: u16, state: &mut Chip8State, memory: &mut Vec<u8>) ->
             state.pc += 2;
             }
         0xC000 => {
-            state.v[((opcode & 0x0F00) >> 8) as usize] = ((opcode & 0x00FF) as u8) & (rand::thread_rng().gen::<u8>() & 0xFF);
+            state.v[((opcode & 0x0F00) >> 8) as usize] = ((opcode & 0x00FF) as u8) & next_random(state);
             state.pc += 2;
             }
         0xD000 => {
             //println!("Draw sprite");
-            let x = state.v[((opcode & 0x0F00) >> 8) as usize] as u16;
-            let y = state.v[((opcode & 0x00F0) >> 4) as usize] as u16;
-            let height = opcode & 0x000F;
+            let x = state.v[((opcode & 0x0F00) >> 8) as usize] as usize;
+            let y = state.v[((opcode & 0x00F0) >> 4) as usize] as usize;
+            let height = (opcode & 0x000F) as usize;
+            // Rows past the end of memory aren't drawn.
+            let start = std::cmp::min(state.index as usize, memory.len());
+            let end = std::cmp::min(start + height, memory.len());
+            let sprite = &memory[start..end];
             
-            // Reset carry flag
-            state.v[0xF] = 0;
-            
-            for yline in 0..height {
-                let pixel = memory[(state.index + yline) as usize];
-                for xline in 0..8 {
-                    if (pixel & (0x80 >> xline)) != 0 {
-                        if state.gfx[(x + xline + ((y + yline) * 64)) as usize] == 1 {
-                            state.v[0xF] = 1;
-                        }
-                        state.gfx[(x + xline + ((y + yline) * 64)) as usize] ^= 1;
-                    }
-                }
-            }
+            // Set the carry flag on collision
+            state.v[0xF] = state.gfx.draw_sprite(x, y, sprite, state.quirks.wrap_sprites) as u8;
             draw_flag = true;
             state.pc += 2;
         }
@@ -444,7 +566,7 @@ fn execute_opcode(opcode: u16, state: &mut Chip8State, memory: &mut Vec<u8>) ->
                     }
                 }
                 _ => {
-                    println!("Unknown opcode: {:#X}", opcode);
+                    eprintln!("Unknown opcode: {:#X}", opcode);
                     }
             }
             state.pc += 2;
@@ -459,17 +581,35 @@ fn execute_opcode(opcode: u16, state: &mut Chip8State, memory: &mut Vec<u8>) ->
                 }
                 0x000A => {
                     //println!("Wait for keypress");
-                    let mut pressed = false;
-                    for x in 0..16 {
-                        if state.key_press[x] == 1 {
-                            pressed = true;
-                            state.v[((opcode & 0x0F00) >> 8) as usize] = x as u8;
-                            //println!("pressed");
-                            break;
+                    // The VIP waits for a key to go down and then come back
+                    // up, so a key held since the last FX0A only counts once.
+                    // Timers keep running while this instruction repeats.
+                    advance = false;
+                    state.waiting_for_key = true;
+                    match state.wait_key_pressed {
+                        None => {
+                            for x in 0..16 {
+                                if state.key_press[x] == 1 {
+                                    if state.quirks.key_wait_on_press {
+                                        state.v[((opcode & 0x0F00) >> 8) as usize] = x as u8;
+                                        state.waiting_for_key = false;
+                                        advance = true;
+                                    } else {
+                                        state.wait_key_pressed = Some(x);
+                                    }
+                                    //println!("pressed");
+                                    break;
+                                }
+                            }
+                        }
+                        Some(x) => {
+                            if state.key_press[x] == 0 {
+                                state.v[((opcode & 0x0F00) >> 8) as usize] = x as u8;
+                                state.waiting_for_key = false;
+                                state.wait_key_pressed = None;
+                                advance = true;
+                            }
                         }
-                    }
-                    if !pressed {
-                        advance = false;
                     }
                     }
                 0x0015 => {
@@ -506,7 +646,7 @@ fn execute_opcode(opcode: u16, state: &mut Chip8State, memory: &mut Vec<u8>) ->
                         }
                     }
                 _ => {
-                    println!("Unknown opcode: {:#X}", opcode);
+                    eprintln!("Unknown opcode: {:#X}", opcode);
                     }
                 }
             if advance {
@@ -514,13 +654,24 @@ fn execute_opcode(opcode: u16, state: &mut Chip8State, memory: &mut Vec<u8>) ->
             }
             }
         _ => {
-            println!("Unknown opcode: {:#X}", opcode);
+            eprintln!("Unknown opcode: {:#X}", opcode);
             state.pc += 2;
             }
     }
     draw_flag
 }
 
+fn next_random(state: &mut Chip8State) -> u8 {
+    // Xorshift gets stuck on zero.
+    if state.rng == 0 {
+        state.rng = 0x2545F491;
+    }
+    state.rng ^= state.rng << 13;
+    state.rng ^= state.rng >> 17;
+    state.rng ^= state.rng << 5;
+    (state.rng >> 24) as u8
+}
+
 fn update_timers(state: &mut Chip8State) {
     if state.delay_timer > 0 {
         state.delay_timer -= 1;
@@ -0,0 +1,78 @@
+use {Chip8State, emulate_cycle};
+use compiled;
+use engine::{self, Cache, Engine};
+
+// Something that watches the emulator run, such as the tracer. Monitors
+// see the machine before and after every instruction, and a debugger can
+// stop it or change it before an instruction runs.
+pub trait Monitor {
+    fn before(&mut self, _state: &mut Chip8State, _memory: &mut Vec<u8>) {}
+
+    fn after(&mut self, _state: &Chip8State, _memory: &[u8]) {}
+
+    // Called once per 60 Hz frame, e.g. to flush output.
+    fn end_frame(&mut self) {}
+}
+
+#[derive(Default)]
+pub struct Monitors {
+    monitors: Vec<Box<dyn Monitor>>,
+    // Decoded instructions, unless running with the interpreter.
+    cache: Option<Box<dyn Cache>>
+}
+
+impl Monitors {
+    pub fn add(&mut self, monitor: Box<dyn Monitor>) {
+        self.monitors.push(monitor);
+    }
+
+    pub fn set_engine(&mut self, engine: Engine) {
+        self.cache = engine::create_cache(engine);
+    }
+
+    // Runs one instruction, returning whether the screen was drawn.
+    pub fn cycle(&mut self, state: &mut Chip8State, memory: &mut Vec<u8>) -> bool {
+        for monitor in self.monitors.iter_mut() {
+            monitor.before(state, memory);
+        }
+        let drawn = emulate_cycle(state, memory);
+        for monitor in self.monitors.iter_mut() {
+            monitor.after(state, memory);
+        }
+        drawn
+    }
+
+    // Runs `cycles` instructions, calling `drawn` after each one that draws.
+    // When no monitor needs to see every instruction, the selected engine
+    // runs if it isn't the interpreter, and otherwise code compiled into
+    // the emulator ahead of time runs wherever there is some.
+    pub fn run<F: FnMut(&Chip8State)>(&mut self, state: &mut Chip8State, memory: &mut Vec<u8>, cycles: u32, mut drawn: F) {
+        let mut budget = cycles;
+        while budget > 0 {
+            if self.monitors.is_empty() {
+                if let Some(ref mut cache) = self.cache {
+                    if cache.run(state, memory, &mut budget) {
+                        drawn(state);
+                    }
+                    continue;
+                }
+                if let Some(compiled_drawn) = compiled::run(state, memory, &mut budget) {
+                    if compiled_drawn {
+                        drawn(state);
+                    }
+                    continue;
+                }
+            }
+            if self.cycle(state, memory) {
+                drawn(state);
+            }
+            budget -= 1;
+        }
+    }
+
+    pub fn end_frame(&mut self) {
+        for monitor in self.monitors.iter_mut() {
+            monitor.end_frame();
+        }
+    }
+}
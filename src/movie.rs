@@ -0,0 +1,313 @@
+use std::fs::File;
+use std::io::prelude::*;
+use std::io::{BufReader, BufWriter};
+
+use config::Options;
+use framebuffer::Framebuffer;
+use quirks;
+use quirks::Quirks;
+
+const MOVIE_HEADER: &str = "chip8-movie 1";
+
+// A checkpoint is written this often while recording.
+const CHECKPOINT_FRAMES: u64 = 60;
+
+// Everything needed to replay a run exactly: the machine settings and the
+// CHIP-8 keys held in each 60 Hz frame. The file is plain text:
+//
+//   chip8-movie 1
+//   rom 8f3c1a2e4b5d6c7f
+//   seed 1a2b3c4d
+//   cycles_per_frame 10
+//   quirk key_wait_on_press false
+//   k 0 0000           keys held from frame 0, as a mask with bit n for key n
+//   k 95 0020
+//   c 120 c0ffee...    framebuffer hash at the end of frame 120
+//   e 200              number of frames, when recording stopped cleanly
+//
+// Key lines are only written when the keys change.
+#[derive(Debug, Default, Clone, PartialEq)]
+pub struct Movie {
+    pub rom_hash: u64,
+    pub seed: u32,
+    pub cycles_per_frame: u32,
+    pub quirks: Quirks,
+    pub key_changes: Vec<(u64, u16)>,
+    pub checkpoints: Vec<(u64, u64)>,
+    pub frames: u64
+}
+
+// 64 bit FNV-1a, used for the ROM and framebuffer hashes.
+pub fn hash(data: &[u8]) -> u64 {
+    hash_bytes(data.iter().cloned())
+}
+
+// The same hash, for bytes that aren't in memory all at once.
+pub fn hash_bytes<I: Iterator<Item = u8>>(bytes: I) -> u64 {
+    let mut hash: u64 = 0xcbf29ce484222325;
+    for byte in bytes {
+        hash ^= byte as u64;
+        hash = hash.wrapping_mul(0x100000001b3);
+    }
+    hash
+}
+
+pub fn key_mask(key_press: &[u8]) -> u16 {
+    let mut mask = 0;
+    for (key, &pressed) in key_press.iter().enumerate() {
+        if pressed != 0 {
+            mask |= 1 << key;
+        }
+    }
+    mask
+}
+
+impl Movie {
+    // Frames covered by the movie. If recording was cut short this is up to
+    // the last key change or checkpoint.
+    pub fn length(&self) -> u64 {
+        let last_keys = self.key_changes.last().map(|&(frame, _)| frame + 1).unwrap_or(0);
+        let last_checkpoint = self.checkpoints.last().map(|&(frame, _)| frame + 1).unwrap_or(0);
+        ::std::cmp::max(self.frames, ::std::cmp::max(last_keys, last_checkpoint))
+    }
+
+    fn header(&self) -> String {
+        let mut header = format!("{}\nrom {:016x}\nseed {:08x}\ncycles_per_frame {}\n",
+                                 MOVIE_HEADER, self.rom_hash, self.seed, self.cycles_per_frame);
+        for name in quirks::quirk_names() {
+            header.push_str(&format!("quirk {} {}\n", name, quirks::get_quirk(&self.quirks, name)));
+        }
+        header
+    }
+}
+
+pub fn parse_movie(text: &str) -> Result<Movie, String> {
+    let mut movie: Movie = Default::default();
+    let mut lines = text.lines().enumerate();
+    match lines.next() {
+        Some((_, line)) if line.trim() == MOVIE_HEADER => (),
+        _ => return Err("Not a movie file".to_string())
+    }
+    for (line_number, line) in lines {
+        let fields: Vec<&str> = line.split_whitespace().collect();
+        let error = || format!("Movie line {}: could not read '{}'", line_number + 1, line);
+        match fields.len() {
+            0 => continue,
+            2 => {
+                match fields[0] {
+                    "rom" => movie.rom_hash = u64::from_str_radix(fields[1], 16).map_err(|_| error())?,
+                    "seed" => movie.seed = u32::from_str_radix(fields[1], 16).map_err(|_| error())?,
+                    "cycles_per_frame" => movie.cycles_per_frame = fields[1].parse().map_err(|_| error())?,
+                    "e" => movie.frames = fields[1].parse().map_err(|_| error())?,
+                    _ => return Err(error())
+                }
+            }
+            3 => {
+                match fields[0] {
+                    "k" => {
+                        let frame = fields[1].parse().map_err(|_| error())?;
+                        let mask = u16::from_str_radix(fields[2], 16).map_err(|_| error())?;
+                        movie.key_changes.push((frame, mask));
+                    }
+                    "c" => {
+                        let frame = fields[1].parse().map_err(|_| error())?;
+                        let gfx_hash = u64::from_str_radix(fields[2], 16).map_err(|_| error())?;
+                        movie.checkpoints.push((frame, gfx_hash));
+                    }
+                    "quirk" => {
+                        let value = fields[2].parse().map_err(|_| error())?;
+                        quirks::set_quirk(&mut movie.quirks, fields[1], value)?;
+                    }
+                    _ => return Err(error())
+                }
+            }
+            _ => return Err(error())
+        }
+    }
+    if movie.cycles_per_frame == 0 {
+        return Err("Movie has no cycles_per_frame".to_string());
+    }
+    Ok(movie)
+}
+
+pub fn load_movie(path: &str) -> Result<Movie, String> {
+    let mut text = String::new();
+    match File::open(path) {
+        Ok(f) => {
+            if let Err(e) = BufReader::new(f).read_to_string(&mut text) {
+                return Err(format!("Could not read movie '{}': {}", path, e));
+            }
+        }
+        Err(e) => return Err(format!("Could not open movie '{}': {}", path, e))
+    }
+    parse_movie(&text)
+}
+
+// Writes the movie as it is recorded, so that nothing is lost when the
+// window is closed.
+pub struct Recorder {
+    out: BufWriter<File>,
+    last_mask: Option<u16>,
+    frames: u64
+}
+
+impl Recorder {
+    pub fn create(path: &str, movie: &Movie) -> Result<Recorder, String> {
+        let f = match File::create(path) {
+            Ok(f) => f,
+            Err(e) => return Err(format!("Could not create movie '{}': {}", path, e))
+        };
+        let mut out = BufWriter::new(f);
+        out.write_all(movie.header().as_bytes()).unwrap();
+        Ok(Recorder { out, last_mask: None, frames: 0 })
+    }
+
+    pub fn record_keys(&mut self, frame: u64, key_press: &[u8]) {
+        let mask = key_mask(key_press);
+        if self.last_mask != Some(mask) {
+            writeln!(self.out, "k {} {:04x}", frame, mask).unwrap();
+            // The process may be killed rather than stopped cleanly, so
+            // keep the file replayable up to here.
+            self.out.flush().unwrap();
+            self.last_mask = Some(mask);
+        }
+    }
+
+    pub fn end_frame(&mut self, frame: u64, gfx: &Framebuffer) {
+        self.frames = frame + 1;
+        if self.frames % CHECKPOINT_FRAMES == 0 {
+            writeln!(self.out, "c {} {:016x}", frame, gfx.hash()).unwrap();
+            self.out.flush().unwrap();
+        }
+    }
+}
+
+impl Drop for Recorder {
+    fn drop(&mut self) {
+        let _ = writeln!(self.out, "e {}", self.frames);
+    }
+}
+
+pub struct Player {
+    movie: Movie,
+    next_change: usize,
+    next_checkpoint: usize,
+    mask: u16,
+    verify: bool
+}
+
+impl Player {
+    pub fn new(movie: Movie, verify: bool) -> Player {
+        Player { movie, next_change: 0, next_checkpoint: 0, mask: 0, verify }
+    }
+
+    pub fn finished(&self, frame: u64) -> bool {
+        frame >= self.movie.length()
+    }
+
+    // Replaces whatever the frontend read with the recorded keys.
+    pub fn play_keys(&mut self, frame: u64, key_press: &mut [u8]) {
+        while self.next_change < self.movie.key_changes.len() && self.movie.key_changes[self.next_change].0 <= frame {
+            self.mask = self.movie.key_changes[self.next_change].1;
+            self.next_change += 1;
+        }
+        for (key, pressed) in key_press.iter_mut().enumerate() {
+            *pressed = ((self.mask >> key) & 1) as u8;
+        }
+    }
+
+    pub fn end_frame(&mut self, frame: u64, gfx: &Framebuffer) -> Result<(), String> {
+        while self.next_checkpoint < self.movie.checkpoints.len() && self.movie.checkpoints[self.next_checkpoint].0 <= frame {
+            let (checkpoint_frame, expected) = self.movie.checkpoints[self.next_checkpoint];
+            self.next_checkpoint += 1;
+            if self.verify && checkpoint_frame == frame && gfx.hash() != expected {
+                return Err(format!("Movie desynced at frame {}: framebuffer hash {:016x}, expected {:016x}",
+                                   frame, gfx.hash(), expected));
+            }
+        }
+        Ok(())
+    }
+}
+
+// Recording or playback for one run, driven once per frame by the frontend.
+#[derive(Default)]
+pub struct MovieSession {
+    recorder: Option<Recorder>,
+    player: Option<Player>,
+    frame: u64
+}
+
+impl MovieSession {
+    pub fn recording(recorder: Recorder) -> MovieSession {
+        MovieSession { recorder: Some(recorder), player: None, frame: 0 }
+    }
+
+    pub fn playing(player: Player) -> MovieSession {
+        MovieSession { recorder: None, player: Some(player), frame: 0 }
+    }
+
+    pub fn frame(&self) -> u64 {
+        self.frame
+    }
+
+    pub fn is_playing(&self) -> bool {
+        match self.player {
+            Some(ref player) => !player.finished(self.frame),
+            None => false
+        }
+    }
+
+    // Called after the frontend has read its input for the frame.
+    pub fn begin_frame(&mut self, key_press: &mut [u8]) {
+        let frame = self.frame;
+        if let Some(ref mut player) = self.player {
+            if !player.finished(frame) {
+                player.play_keys(frame, key_press);
+            }
+        }
+        if let Some(ref mut recorder) = self.recorder {
+            recorder.record_keys(frame, key_press);
+        }
+    }
+
+    // Called after the frame's instructions and timers have run.
+    pub fn end_frame(&mut self, gfx: &Framebuffer) -> Result<(), String> {
+        let frame = self.frame;
+        self.frame += 1;
+        if let Some(ref mut recorder) = self.recorder {
+            recorder.end_frame(frame, gfx);
+        }
+        match self.player {
+            Some(ref mut player) => player.end_frame(frame, gfx),
+            None => Ok(())
+        }
+    }
+}
+
+// Sets up recording or playback from the options. Playback takes the seed,
+// speed and quirks from the movie, so they match the recorded run.
+pub fn start_session(options: &mut Options, rom: &[u8]) -> Result<MovieSession, String> {
+    if let Some(path) = options.play_movie.clone() {
+        let movie = load_movie(&path)?;
+        if movie.rom_hash != hash(rom) {
+            return Err(format!("Movie '{}' was recorded with a different ROM", path));
+        }
+        options.seed = Some(movie.seed);
+        options.cycles_per_frame = movie.cycles_per_frame;
+        options.quirks = movie.quirks;
+        return Ok(MovieSession::playing(Player::new(movie, options.verify_movie)));
+    }
+    if let Some(ref path) = options.record_movie {
+        let movie = Movie {
+            rom_hash: hash(rom),
+            seed: options.seed.unwrap_or(0),
+            cycles_per_frame: options.cycles_per_frame,
+            quirks: options.quirks,
+            key_changes: Vec::new(),
+            checkpoints: Vec::new(),
+            frames: 0
+        };
+        return Ok(MovieSession::recording(Recorder::create(path, &movie)?));
+    }
+    Ok(Default::default())
+}
@@ -0,0 +1,60 @@
+// Display colors. Index 0 is the background and index 1 the foreground;
+// indices 2 and 3 are only used by XO-CHIP style two plane drawing, where a
+// pixel set in plane 2 only uses color 2 and a pixel set in both uses color 3.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct Palette {
+    pub colors: [[u8; 3]; 4]
+}
+
+const BUILTIN_PALETTES: [(&str, Palette); 5] = [
+    ("classic", Palette { colors: [[0x00, 0x00, 0x00], [0xFF, 0xFF, 0xFF], [0xAA, 0xAA, 0xAA], [0x55, 0x55, 0x55]] }),
+    ("green", Palette { colors: [[0x0A, 0x1A, 0x0A], [0x33, 0xFF, 0x66], [0x1F, 0x99, 0x3D], [0x14, 0x66, 0x29]] }),
+    ("amber", Palette { colors: [[0x1A, 0x10, 0x00], [0xFF, 0xB0, 0x00], [0x99, 0x6A, 0x00], [0x66, 0x46, 0x00]] }),
+    ("lcd", Palette { colors: [[0x9B, 0xBC, 0x0F], [0x0F, 0x38, 0x0F], [0x30, 0x62, 0x30], [0x8B, 0xAC, 0x0F]] }),
+    ("octo", Palette { colors: [[0x99, 0x66, 0x00], [0xFF, 0xCC, 0x00], [0xFF, 0x66, 0x00], [0x66, 0x22, 0x00]] })
+];
+
+impl Default for Palette {
+    fn default() -> Palette {
+        BUILTIN_PALETTES[0].1
+    }
+}
+
+pub fn builtin_palette(name: &str) -> Option<Palette> {
+    for &(palette_name, palette) in BUILTIN_PALETTES.iter() {
+        if palette_name == name.to_lowercase() {
+            return Some(palette);
+        }
+    }
+    None
+}
+
+pub fn builtin_palette_names() -> Vec<&'static str> {
+    BUILTIN_PALETTES.iter().map(|&(name, _)| name).collect()
+}
+
+// Parses "#RRGGBB", "RRGGBB" or "0xRRGGBB".
+pub fn parse_color(text: &str) -> Result<[u8; 3], String> {
+    let trimmed = text.trim();
+    let hex = if trimmed.starts_with('#') {
+        &trimmed[1..]
+    } else if trimmed.starts_with("0x") || trimmed.starts_with("0X") {
+        &trimmed[2..]
+    } else {
+        trimmed
+    };
+    if hex.len() != 6 {
+        return Err(format!("Invalid color '{}', expected #RRGGBB", text));
+    }
+    match u32::from_str_radix(hex, 16) {
+        Ok(rgb) => Ok([(rgb >> 16) as u8, (rgb >> 8) as u8, rgb as u8]),
+        Err(_) => Err(format!("Invalid color '{}', expected #RRGGBB", text))
+    }
+}
+
+impl Palette {
+    // Color for a pixel value, where bit 0 is plane 1 and bit 1 is plane 2.
+    pub fn color(&self, pixel: u8) -> [u8; 3] {
+        self.colors[(pixel & 0x3) as usize]
+    }
+}
@@ -0,0 +1,185 @@
+use std::collections::HashMap;
+use std::fs::File;
+use std::io::prelude::*;
+use std::io::BufWriter;
+
+use {Chip8State, get_opcode};
+use debuginfo::DebugInfo;
+use disasm::{self, Instruction};
+use monitor::Monitor;
+
+// Rewrite the profile this often, in case the process is killed rather
+// than stopped cleanly.
+const WRITE_EVERY_FRAMES: u32 = 60;
+
+// Counts the instructions run under each call stack. A stack is the entry
+// address of each subroutine on it, outermost first, and follows the CALL
+// and RET instructions the program runs.
+pub struct Profiler {
+    path: String,
+    debug_info: DebugInfo,
+    frames: Vec<usize>,
+    counts: HashMap<Vec<usize>, u64>,
+    opcode: u16,
+    frames_since_write: u32
+}
+
+impl Profiler {
+    pub fn create(path: &str, debug_info: DebugInfo) -> Result<Profiler, String> {
+        // Check the file can be written before running anything.
+        if let Err(e) = File::create(path) {
+            return Err(format!("Could not create profile '{}': {}", path, e));
+        }
+        Ok(Profiler {
+            path: path.to_string(),
+            debug_info,
+            frames: Vec::new(),
+            counts: HashMap::new(),
+            opcode: 0,
+            frames_since_write: 0
+        })
+    }
+
+    // One line per call stack, outermost subroutine first and separated by
+    // semicolons, then the number of instructions run there. This is the
+    // folded format flame graph tools read.
+    pub fn folded(&self) -> Vec<String> {
+        let root = self.debug_info.label_at(0x200).unwrap_or("main");
+        let mut lines: Vec<String> = self.counts.iter().map(|(frames, count)| {
+            let mut names = vec![root.to_string()];
+            names.extend(frames.iter().map(|&entry| self.debug_info.subroutine_name(entry)));
+            format!("{} {}", names.join(";"), count)
+        }).collect();
+        lines.sort();
+        lines
+    }
+
+    fn write(&self) -> Result<(), String> {
+        let error = |e: ::std::io::Error| format!("Could not write profile '{}': {}", self.path, e);
+        let mut out = BufWriter::new(File::create(&self.path).map_err(error)?);
+        for line in self.folded() {
+            writeln!(out, "{}", line).map_err(error)?;
+        }
+        out.flush().map_err(error)
+    }
+}
+
+impl Monitor for Profiler {
+    fn before(&mut self, state: &mut Chip8State, memory: &mut Vec<u8>) {
+        self.opcode = get_opcode(state.pc, memory);
+        // Look the stack up by slice so the common case doesn't allocate.
+        if let Some(count) = self.counts.get_mut(&self.frames[..]) {
+            *count += 1;
+            return;
+        }
+        self.counts.insert(self.frames.clone(), 1);
+    }
+
+    fn after(&mut self, state: &Chip8State, _memory: &[u8]) {
+        // Follow the real stack pointer rather than counting CALLs and RETs,
+        // so a debugger changing it can't leave the profile out of step.
+        if state.stack_pointer < self.frames.len() {
+            self.frames.truncate(state.stack_pointer);
+        }
+        while state.stack_pointer > self.frames.len() {
+            let entry = match disasm::decode(self.opcode) {
+                Instruction::Call(entry) => entry as usize,
+                _ => state.pc
+            };
+            self.frames.push(entry);
+        }
+    }
+
+    fn end_frame(&mut self) {
+        self.frames_since_write += 1;
+        if self.frames_since_write >= WRITE_EVERY_FRAMES {
+            self.frames_since_write = 0;
+            if let Err(e) = self.write() {
+                eprintln!("{}", e);
+            }
+        }
+    }
+}
+
+impl Drop for Profiler {
+    fn drop(&mut self) {
+        if let Err(e) = self.write() {
+            eprintln!("{}", e);
+        }
+    }
+}
+
+// Instructions run in each subroutine: inclusive counts everything run
+// while it was on the stack, exclusive only what ran in its own code.
+#[derive(Debug, Clone, PartialEq)]
+pub struct SubroutineCounts {
+    pub name: String,
+    pub inclusive: u64,
+    pub exclusive: u64
+}
+
+// Totals folded stacks by subroutine, most inclusive first.
+pub fn subroutine_counts(folded: &str) -> Result<Vec<SubroutineCounts>, String> {
+    let mut totals: Vec<SubroutineCounts> = Vec::new();
+    for (line_number, line) in folded.lines().enumerate() {
+        if line.trim().is_empty() {
+            continue;
+        }
+        let error = || format!("Profile line {}: could not read '{}'", line_number + 1, line);
+        let split = line.rfind(' ').ok_or_else(&error)?;
+        let count: u64 = line[split + 1..].trim().parse().map_err(|_| error())?;
+        let names: Vec<&str> = line[..split].split(';').collect();
+        for (depth, &name) in names.iter().enumerate() {
+            let leaf = depth == names.len() - 1;
+            // Recursion puts a subroutine on the stack more than once, but
+            // its inclusive count only takes each instruction once.
+            let outermost = !names[..depth].contains(&name);
+            let index = match totals.iter().position(|totals| totals.name == name) {
+                Some(index) => index,
+                None => {
+                    totals.push(SubroutineCounts { name: name.to_string(), inclusive: 0, exclusive: 0 });
+                    totals.len() - 1
+                }
+            };
+            if outermost {
+                totals[index].inclusive += count;
+            }
+            if leaf {
+                totals[index].exclusive += count;
+            }
+        }
+    }
+    totals.sort_by(|a, b| b.inclusive.cmp(&a.inclusive).then(b.exclusive.cmp(&a.exclusive)).then(a.name.cmp(&b.name)));
+    Ok(totals)
+}
+
+pub fn report(totals: &[SubroutineCounts]) -> String {
+    let total: u64 = totals.iter().map(|totals| totals.exclusive).sum();
+    let percent = |count: u64| if total > 0 { count as f64 * 100.0 / total as f64 } else { 0.0 };
+    let width = totals.iter().map(|totals| totals.name.len()).max().unwrap_or(0).max(10);
+    let mut report = format!("{:<width$} {:>20} {:>20}\n", "Subroutine", "Inclusive", "Exclusive", width = width);
+    for counts in totals {
+        report.push_str(&format!("{:<width$} {:>12} {:>6.1}% {:>12} {:>6.1}%\n",
+                                 counts.name, counts.inclusive, percent(counts.inclusive),
+                                 counts.exclusive, percent(counts.exclusive), width = width));
+    }
+    report
+}
+
+pub fn report_command(args: &[String]) -> Result<(), String> {
+    if args.len() != 1 {
+        return Err("Usage: rust_chip8 profile-report <profile>".to_string());
+    }
+    let path = &args[0];
+    let mut text = String::new();
+    match File::open(path) {
+        Ok(mut f) => {
+            if let Err(e) = f.read_to_string(&mut text) {
+                return Err(format!("Could not read profile '{}': {}", path, e));
+            }
+        }
+        Err(e) => return Err(format!("Could not open profile '{}': {}", path, e))
+    }
+    print!("{}", report(&subroutine_counts(&text)?));
+    Ok(())
+}
@@ -0,0 +1,34 @@
+// Behaviors that differ between CHIP-8 interpreters. The defaults follow the
+// original COSMAC VIP interpreter.
+#[derive(Debug, Default, Clone, Copy, PartialEq)]
+pub struct Quirks {
+    // FX0A completes as soon as any key is down, instead of waiting for a
+    // key to be pressed and released.
+    pub key_wait_on_press: bool,
+    // Sprites running off an edge of the screen come back on the opposite
+    // one, instead of being cut off.
+    pub wrap_sprites: bool
+}
+
+const QUIRK_NAMES: [&str; 2] = ["key_wait_on_press", "wrap_sprites"];
+
+pub fn quirk_names() -> Vec<&'static str> {
+    QUIRK_NAMES.to_vec()
+}
+
+pub fn set_quirk(quirks: &mut Quirks, name: &str, value: bool) -> Result<(), String> {
+    match name {
+        "key_wait_on_press" => quirks.key_wait_on_press = value,
+        "wrap_sprites" => quirks.wrap_sprites = value,
+        _ => return Err(format!("Unknown quirk '{}', expected one of: {}", name, QUIRK_NAMES.join(", ")))
+    }
+    Ok(())
+}
+
+pub fn get_quirk(quirks: &Quirks, name: &str) -> bool {
+    match name {
+        "key_wait_on_press" => quirks.key_wait_on_press,
+        "wrap_sprites" => quirks.wrap_sprites,
+        _ => false
+    }
+}
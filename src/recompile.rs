@@ -0,0 +1,174 @@
+use std::fs::File;
+use std::io::prelude::*;
+
+use analysis::{self, Analysis, Note};
+use disasm::{self, Instruction};
+
+// A straight run of instructions to compile into one function: a block
+// from the analysis, or the part of one after a call, since a return can
+// come back there, or after a store, since it may have written over the
+// rest of the block.
+#[derive(Debug, PartialEq)]
+pub struct Segment {
+    pub start: usize,
+    pub end: usize
+}
+
+pub fn segments(analysis: &Analysis) -> Vec<Segment> {
+    // Don't compile code the program is known to write over.
+    let written: Vec<usize> = analysis.notes.iter().filter_map(|note| match *note {
+        Note::SelfModifying(_, target) => Some(target),
+        _ => None
+    }).collect();
+    let mut segments = Vec::new();
+    for block in analysis.blocks.values() {
+        let mut start = block.start;
+        for address in (block.start..block.end).step_by(2) {
+            let instruction = disasm::decode(analysis.opcode(address));
+            let call = matches!(instruction, Instruction::Call(_));
+            let store = !instruction.memory_writes(0).is_empty();
+            if call || store || address + 2 == block.end {
+                if !written.iter().any(|&target| target >= start && target < address + 2) {
+                    segments.push(Segment { start, end: address + 2 });
+                }
+                start = address + 2;
+            }
+        }
+    }
+    segments
+}
+
+// The Rust for one instruction at `address`, which is the last of its
+// segment when `last` is true, and whether it always leaves the segment.
+// Simple instructions are written out, and the rest run through the
+// interpreter so they behave exactly as they would without compiling.
+fn instruction_code(address: usize, opcode: u16, last: bool) -> (String, bool) {
+    let next = address + 2;
+    let spent = format!("    if spent(state, budget) {{\n        state.pc = 0x{:03X};\n        return false;\n    }}\n", next);
+    let simple = |code: String| (format!("    {}\n{}", code, spent), false);
+    let leave = |code: String| (format!("    {}\n    spent(state, budget);\n    false\n", code), true);
+    match disasm::decode(opcode) {
+        Instruction::Load(x, nn) => simple(format!("state.v[{}] = 0x{:02X};", x, nn)),
+        Instruction::Add(x, nn) => simple(format!("state.v[{0}] = state.v[{0}].wrapping_add(0x{1:02X});", x, nn)),
+        Instruction::Move(x, y) => simple(format!("state.v[{}] = state.v[{}];", x, y)),
+        Instruction::Or(x, y) => simple(format!("state.v[{}] |= state.v[{}];", x, y)),
+        Instruction::And(x, y) => simple(format!("state.v[{}] &= state.v[{}];", x, y)),
+        Instruction::Xor(x, y) => simple(format!("state.v[{}] ^= state.v[{}];", x, y)),
+        Instruction::LoadIndex(nnn) => simple(format!("state.index = 0x{:03X};", nnn)),
+        Instruction::AddIndex(x) => simple(format!("state.index += u16::from(state.v[{}]);", x)),
+        Instruction::Font(x) => simple(format!("state.index = u16::from(state.v[{}]) * 5;", x)),
+        Instruction::ReadDelay(x) => simple(format!("state.v[{}] = state.delay_timer as u8;", x)),
+        Instruction::SetDelay(x) => simple(format!("state.delay_timer = u16::from(state.v[{}]);", x)),
+        Instruction::Jump(nnn) => leave(format!("state.pc = 0x{:03X};", nnn)),
+        Instruction::Call(nnn) => leave(format!(
+            "state.stack[state.stack_pointer] = 0x{:03X};\n    state.stack_pointer += 1;\n    state.pc = 0x{:03X};",
+            address, nnn)),
+        Instruction::Return => leave(
+            "state.stack_pointer -= 1;\n    state.pc = state.stack[state.stack_pointer] + 2;".to_string()),
+        Instruction::SkipEqual(x, nn) => leave(format!(
+            "state.pc = if state.v[{}] == 0x{:02X} {{ 0x{:03X} }} else {{ 0x{:03X} }};", x, nn, next + 2, next)),
+        Instruction::SkipNotEqual(x, nn) => leave(format!(
+            "state.pc = if state.v[{}] != 0x{:02X} {{ 0x{:03X} }} else {{ 0x{:03X} }};", x, nn, next + 2, next)),
+        Instruction::SkipEqualReg(x, y) => leave(format!(
+            "state.pc = if state.v[{}] == state.v[{}] {{ 0x{:03X} }} else {{ 0x{:03X} }};", x, y, next + 2, next)),
+        _ if last => (format!("    let drawn = interpret(state, memory, 0x{:03X}, 0x{:04X});\n    spent(state, budget);\n    drawn\n",
+                              address, opcode), true),
+        _ => (format!("    let drawn = interpret(state, memory, 0x{:03X}, 0x{:04X});\n\
+                       \x20   if spent(state, budget) || drawn || state.pc != 0x{:03X} {{\n\
+                       \x20       return drawn;\n\
+                       \x20   }}\n", address, opcode, next), false)
+    }
+}
+
+// Rust source for a module running the ROM's code, to build into the
+// emulator in place of src/compiled.rs.
+pub fn recompile(rom: &[u8], rom_name: &str) -> String {
+    let analysis = analysis::analyze(rom);
+    let segments = segments(&analysis);
+    let mut code = format!("// Compiled from {} by `rust_chip8 recompile`.\n\
+                            #![allow(dead_code, unused_variables, clippy::all)]\n\
+                            \n\
+                            use {{Chip8State, execute_opcode}};\n\
+                            \n\
+                            pub const ROM: &[u8] = &[", rom_name);
+    for (i, byte) in rom.iter().enumerate() {
+        code.push_str(if i % 16 == 0 { "\n    " } else { " " });
+        code.push_str(&format!("0x{:02X},", byte));
+    }
+    code.push_str("\n];\n\n");
+    code.push_str("// Runs compiled code from state.pc until `budget` instructions have run, one\n\
+                   // draws, or it reaches code that isn't compiled or has been changed. None\n\
+                   // means there was nothing to run at state.pc.\n\
+                   pub fn run(state: &mut Chip8State, memory: &mut Vec<u8>, budget: &mut u32) -> Option<bool> {\n\
+                   \x20   let mut ran = false;\n\
+                   \x20   while *budget > 0 {\n\
+                   \x20       let drawn = match state.pc {\n");
+    for segment in &segments {
+        code.push_str(&format!("            0x{0:03X} if unchanged(memory, ROM, 0x{0:03X}, 0x{1:03X}) => segment_{0:03X}(state, memory, budget),\n",
+                               segment.start, segment.end));
+    }
+    code.push_str("            _ => return if ran { Some(false) } else { None }\n\
+                   \x20       };\n\
+                   \x20       ran = true;\n\
+                   \x20       if drawn {\n\
+                   \x20           return Some(true);\n\
+                   \x20       }\n\
+                   \x20   }\n\
+                   \x20   Some(false)\n\
+                   }\n");
+    for segment in &segments {
+        code.push_str(&format!("\nfn segment_{:03X}(state: &mut Chip8State, memory: &mut Vec<u8>, budget: &mut u32) -> bool {{\n",
+                               segment.start));
+        let mut leaves = false;
+        for address in (segment.start..segment.end).step_by(2) {
+            let opcode = analysis.opcode(address);
+            let (instruction, last_leaves) = instruction_code(address, opcode, address + 2 == segment.end);
+            code.push_str(&format!("    // {:03X}: {}\n", address, disasm::disassemble(opcode)));
+            code.push_str(&instruction);
+            leaves = last_leaves;
+        }
+        // Otherwise run on into the next segment.
+        if !leaves {
+            code.push_str(&format!("    state.pc = 0x{:03X};\n    false\n", segment.end));
+        }
+        code.push_str("}\n");
+    }
+    code.push_str(RUNTIME);
+    code
+}
+
+// What the compiled code calls on.
+const RUNTIME: &str = "
+// Counts an instruction run, returning whether that used up the budget.
+fn spent(state: &mut Chip8State, budget: &mut u32) -> bool {
+    state.cycles += 1;
+    *budget -= 1;
+    *budget == 0
+}
+
+// Whether the code from `start` to `end` is still as compiled.
+fn unchanged(memory: &[u8], rom: &[u8], start: usize, end: usize) -> bool {
+    memory[start..end] == rom[start - 0x200..end - 0x200]
+}
+
+// Runs an instruction through the interpreter.
+fn interpret(state: &mut Chip8State, memory: &mut Vec<u8>, address: usize, opcode: u16) -> bool {
+    state.pc = address;
+    execute_opcode(opcode, state, memory)
+}
+";
+
+pub fn recompile_command(args: &[String]) -> Result<(), String> {
+    if args.len() != 2 {
+        return Err("Usage: rust_chip8 recompile <rom> <output.rs>".to_string());
+    }
+    let rom = analysis::read_rom(&args[0])?;
+    let rom_name = ::std::path::Path::new(&args[0]).file_name()
+        .map(|name| name.to_string_lossy().into_owned()).unwrap_or_default();
+    let code = recompile(&rom, &rom_name);
+    let result = File::create(&args[1]).and_then(|mut f| f.write_all(code.as_bytes()));
+    if let Err(e) = result {
+        return Err(format!("Could not write '{}': {}", args[1], e));
+    }
+    Ok(())
+}
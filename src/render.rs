@@ -0,0 +1,255 @@
+use std::borrow::Cow;
+
+use glium;
+use glium::Surface;
+
+use framebuffer::Framebuffer;
+use palette::Palette;
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum DisplayMode {
+    // Present as soon as an instruction draws, but at most once per 60 Hz
+    // frame, with later drawing shown at the end of the frame after.
+    Immediate,
+    // Present at most once per 60 Hz frame, so sprites erased and redrawn
+    // within a frame never show up half drawn.
+    Vblank,
+    // Present every frame, fading pixels out over several frames like the
+    // phosphor on the VIP's CRT.
+    Phosphor
+}
+
+pub fn parse_display_mode(text: &str) -> Result<DisplayMode, String> {
+    match text {
+        "immediate" => Ok(DisplayMode::Immediate),
+        "vblank" => Ok(DisplayMode::Vblank),
+        "phosphor" => Ok(DisplayMode::Phosphor),
+        _ => Err(format!("Unknown display mode '{}', expected immediate, vblank or phosphor", text))
+    }
+}
+
+// Brightness of each pixel between 0.0 and 1.0. Pixels lit at any point
+// during a frame are at full brightness when it is presented, and lose
+// `decay` of their brightness every frame after they go dark.
+pub struct Phosphor {
+    levels: Vec<f32>,
+    decay: f32
+}
+
+impl Phosphor {
+    pub fn new(decay: f32) -> Phosphor {
+        Phosphor {
+            levels: vec![0.0; 2048],
+            decay
+        }
+    }
+
+    // Called after every draw, so pixels that are erased again before the
+    // end of the frame still light up.
+    pub fn expose(&mut self, gfx: &Framebuffer) {
+        for y in 0..32 {
+            for x in 0..64 {
+                if gfx.pixel(x, y) != 0 {
+                    self.levels[y * 64 + x] = 1.0;
+                }
+            }
+        }
+    }
+
+    // Called once a frame after presenting.
+    pub fn fade(&mut self) {
+        for level in self.levels.iter_mut() {
+            *level *= 1.0 - self.decay;
+            if *level < 1.0 / 255.0 {
+                *level = 0.0;
+            }
+        }
+    }
+
+    // Whether anything is still lit, and so needs presenting this frame.
+    pub fn is_lit(&self) -> bool {
+        self.levels.iter().any(|&level| level > 0.0)
+    }
+
+    pub fn image(&self, palette: &Palette) -> Vec<u8> {
+        let background = palette.colors[0];
+        let foreground = palette.colors[1];
+        let mut image_data = vec![0u8; 2048 * 3];
+        for y in 0..32 {
+            for x in 0..64 {
+                let level = self.levels[y * 64 + x];
+                let offset = (y * 64 + x) * 3;
+                for c in 0..3 {
+                    let blended = background[c] as f32 + (foreground[c] as f32 - background[c] as f32) * level;
+                    image_data[offset + c] = blended.round() as u8;
+                }
+            }
+        }
+        image_data
+    }
+}
+
+// RGB image of the screen, top row first. The quad it is drawn on puts the
+// first row at the top.
+pub fn frame_image(gfx: &Framebuffer, palette: &Palette) -> Vec<u8> {
+    let mut image_data = vec![0u8; 2048 * 3];
+    for y in 0..32 {
+        for x in 0..64 {
+            let color = palette.color(gfx.pixel(x, y));
+            let offset = (y * 64 + x) * 3;
+            image_data[offset..offset + 3].copy_from_slice(&color);
+        }
+    }
+    image_data
+}
+
+// Where the screen goes in a window, as (left, bottom, width, height) in
+// window pixels: scaled by the largest whole number that leaves at least
+// `border` pixels on every side, or by 1 if none does, and centered.
+pub fn screen_viewport(window: (u32, u32), border: u32) -> (u32, u32, u32, u32) {
+    let (window_width, window_height) = window;
+    let across = window_width.saturating_sub(border.saturating_mul(2)) / 64;
+    let down = window_height.saturating_sub(border.saturating_mul(2)) / 32;
+    let scale = ::std::cmp::max(1, ::std::cmp::min(across, down));
+    let (width, height) = (64 * scale, 32 * scale);
+    (window_width.saturating_sub(width) / 2, window_height.saturating_sub(height) / 2, width, height)
+}
+
+#[derive(Copy, Clone)]
+struct Vertex {
+    position: [f32; 2],
+    tex_coords: [f32; 2]
+}
+
+implement_vertex!(Vertex, position, tex_coords);
+
+const VERTEX_SHADER: &str = r#"
+    #version 140
+    in vec2 position;
+    in vec2 tex_coords;
+    out vec2 v_tex_coords;
+    void main() {
+        v_tex_coords = tex_coords;
+        gl_Position = vec4(position, 0.0, 1.0);
+    }
+"#;
+
+const FRAGMENT_SHADER: &str = r#"
+    #version 140
+    in vec2 v_tex_coords;
+    out vec4 color;
+    uniform sampler2D tex;
+    void main() {
+        color = texture(tex, v_tex_coords);
+    }
+"#;
+
+// Decides which frames get presented: only those that differ from what is
+// already on screen, and at most one between vertical blanks.
+#[derive(Default)]
+pub struct Pacer {
+    presented: Vec<u8>,
+    presented_this_frame: bool
+}
+
+impl Pacer {
+    pub fn presented_this_frame(&self) -> bool {
+        self.presented_this_frame
+    }
+
+    // Whether to present `image_data` now, taking it as presented if so.
+    pub fn present(&mut self, image_data: &[u8]) -> bool {
+        if self.presented_this_frame || self.presented[..] == image_data[..] {
+            return false;
+        }
+        self.presented.clear();
+        self.presented.extend_from_slice(image_data);
+        self.presented_this_frame = true;
+        true
+    }
+
+    pub fn vblank(&mut self) {
+        self.presented_this_frame = false;
+    }
+}
+
+// Owns the screen texture for the life of the window, and presents frames
+// when its pacer allows.
+pub struct Renderer {
+    texture: glium::Texture2d,
+    quad: glium::VertexBuffer<Vertex>,
+    program: glium::Program,
+    border: u32,
+    border_color: [u8; 3],
+    pacer: Pacer
+}
+
+impl Renderer {
+    pub fn new(display: &glium::backend::glutin_backend::GlutinFacade, palette: &Palette,
+               border: u32, border_color: [u8; 3]) -> Renderer {
+        let image_data = frame_image(&Default::default(), palette);
+        let screen = glium::texture::RawImage2d {
+                      data: Cow::Borrowed(&image_data[..]),
+                      width: 64,
+                      height: 32,
+                      format: glium::texture::ClientFormat::U8U8U8};
+        // The screen's first row is at the top of the window, where texture
+        // coordinates start.
+        let quad = [
+            Vertex { position: [-1.0, 1.0], tex_coords: [0.0, 0.0] },
+            Vertex { position: [1.0, 1.0], tex_coords: [1.0, 0.0] },
+            Vertex { position: [-1.0, -1.0], tex_coords: [0.0, 1.0] },
+            Vertex { position: [1.0, -1.0], tex_coords: [1.0, 1.0] }
+        ];
+        Renderer {
+            texture: glium::Texture2d::new(display, screen).unwrap(),
+            quad: glium::VertexBuffer::new(display, &quad).unwrap(),
+            program: glium::Program::from_source(display, VERTEX_SHADER, FRAGMENT_SHADER, None).unwrap(),
+            border,
+            border_color,
+            pacer: Pacer { presented: image_data, presented_this_frame: false }
+        }
+    }
+
+    pub fn presented_this_frame(&self) -> bool {
+        self.pacer.presented_this_frame()
+    }
+
+    pub fn present(&mut self, display: &glium::backend::glutin_backend::GlutinFacade, image_data: &[u8]) {
+        if !self.pacer.present(image_data) {
+            return;
+        }
+        let screen = glium::texture::RawImage2d {
+                      data: Cow::Borrowed(image_data),
+                      width: 64,
+                      height: 32,
+                      format: glium::texture::ClientFormat::U8U8U8};
+        self.texture.write(glium::Rect { left: 0, bottom: 0, width: 64, height: 32 }, screen);
+        self.redraw(display);
+    }
+
+    // Draws what was last presented again, such as after the window is
+    // resized.
+    pub fn redraw(&self, display: &glium::backend::glutin_backend::GlutinFacade) {
+        let mut target = display.draw();
+        let color = self.border_color;
+        target.clear_color(color[0] as f32 / 255.0, color[1] as f32 / 255.0, color[2] as f32 / 255.0, 1.0);
+        let (left, bottom, width, height) = screen_viewport(display.get_framebuffer_dimensions(), self.border);
+        let parameters = glium::DrawParameters {
+            viewport: Some(glium::Rect { left, bottom, width, height }),
+            ..Default::default()
+        };
+        let uniforms = uniform! {
+            tex: self.texture.sampled()
+                .magnify_filter(glium::uniforms::MagnifySamplerFilter::Nearest)
+                .minify_filter(glium::uniforms::MinifySamplerFilter::Nearest)
+        };
+        target.draw(&self.quad, glium::index::NoIndices(glium::index::PrimitiveType::TriangleStrip),
+                    &self.program, &uniforms, &parameters).unwrap();
+        target.finish().unwrap();
+    }
+
+    pub fn vblank(&mut self) {
+        self.pacer.vblank();
+    }
+}
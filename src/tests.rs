@@ -1,7 +1,30 @@
 use super::get_opcode;
+use super::emulate_cycle;
 use super::execute_opcode;
 use super::Chip8State;
 use super::init_state;
+use analysis::{analyze, Exit, Note};
+use bench::{self, summarize, WORKLOADS};
+use config::{parse_config, apply_config, ConfigValue, Options};
+use coverage::Coverage;
+use dap::{self, base64_decode, base64_encode};
+use debuginfo::parse_debug_info;
+use decompile::decompile;
+use engine::Engine;
+use disasm::{decode, disassemble, disassemble_with_labels, Class, Instruction};
+use framebuffer::{Framebuffer, Span};
+use gdb::{handle_packet, packet, Action};
+use input::{parse_script, InputSource, Inputs};
+use json::{parse_json, Json};
+use keymap::{from_config, gamepad_from_config, layout_name, Keyboard, Keymap};
+use movie::{hash, parse_movie, key_mask, Movie, Player, Recorder};
+use monitor::{Monitor, Monitors};
+use palette::{builtin_palette, parse_color, Palette};
+use profile::{subroutine_counts, Profiler, SubroutineCounts};
+use recompile::{recompile, segments, Segment};
+use render::{frame_image, screen_viewport, Pacer, Phosphor};
+use trace::{parse_classes, parse_pc_range, parse_text_trace, first_difference, TraceFilter};
+use tui::{screen_lines, key_name_for_char, Glyphs};
 
 #[test]
 fn test_opcode_read() {
@@ -18,13 +41,12 @@ fn test_opcode_00e0() {
     init_state(&mut state);
     
     // Fill graphics with non-zero dat
-    state.gfx = vec![3u8; 2048];
+    state.gfx.draw_sprite(0, 0, &[0xFF], false);
+    state.gfx.draw_sprite(56, 31, &[0xFF], false);
     execute_opcode(0x00E0, &mut state, &mut memory);
     
     // gfx should be all zeroes
-    for x in 0..2048 {
-        assert_eq!(0, state.gfx[x]);
-    }
+    assert!((0..32).all(|y| (0..64).all(|x| state.gfx.pixel(x, y) == 0)));
 }
 
 #[test]
@@ -249,4 +271,841 @@ fn test_opcode_8xy4() {
     assert_eq!(0x76, state.v[2]);
     assert_eq!(0x02, state.v[0xa]);
     assert_eq!(0x00, state.v[0xf]);
-}
\ No newline at end of file
+}
+#[test]
+fn test_parse_config() {
+    let config = parse_config("rom = \"c:\\\\emu\\\\PONG\" # comment\n\
+                               \n\
+                               [display]\n\
+                               palette = \"amber\"\n\
+                               colors = [\"#000000\", \"#FFCC00\"]\n\
+                               names = [\"a,b\", \"c\"]\n").unwrap();
+    
+    assert_eq!(Some(&ConfigValue::Str("c:\\emu\\PONG".to_string())), config.get("rom"));
+    assert_eq!(Some("amber"), config.get_str("display.palette").unwrap());
+    // commas in strings don't split arrays
+    assert_eq!(Some(&ConfigValue::List(vec![ConfigValue::Str("a,b".to_string()), ConfigValue::Str("c".to_string())])),
+               config.get("display.names"));
+    
+    let mut options: Options = Default::default();
+    apply_config(&mut options, &config).unwrap();
+    
+    // explicit colors override the palette
+    assert_eq!([0x00, 0x00, 0x00], options.palette.colors[0]);
+    assert_eq!([0xFF, 0xCC, 0x00], options.palette.colors[1]);
+    assert_eq!(builtin_palette("amber").unwrap().colors[2], options.palette.colors[2]);
+    
+    // decay has to be a fraction
+    let config = parse_config("[display]\ndecay = nan\n").unwrap();
+    assert!(apply_config(&mut options, &config).is_err());
+    
+    // and cycles have to fit in 32 bits
+    let config = parse_config("[cpu]\ncycles_per_frame = 4294967296\n").unwrap();
+    assert!(apply_config(&mut options, &config).is_err());
+}
+
+#[test]
+fn test_parse_color() {
+    assert_eq!([0x12, 0x34, 0x56], parse_color("#123456").unwrap());
+    assert_eq!([0xAB, 0xCD, 0xEF], parse_color("0xabcdef").unwrap());
+    assert!(parse_color("#12345").is_err());
+    assert!(parse_color("#GG0000").is_err());
+}
+
+#[test]
+fn test_phosphor_fade() {
+    let palette = Palette { colors: [[0, 0, 0], [200, 100, 0], [0, 0, 0], [0, 0, 0]] };
+    let mut phosphor = Phosphor::new(0.5);
+    let mut gfx: Framebuffer = Default::default();
+    
+    // the image starts with the top left pixel
+    gfx.draw_sprite(0, 0, &[0x80], false);
+    phosphor.expose(&gfx);
+    assert_eq!(&[200, 100, 0], &phosphor.image(&palette)[..3]);
+    
+    // erased pixels fade out over several frames
+    gfx.draw_sprite(0, 0, &[0x80], false);
+    phosphor.fade();
+    assert_eq!(&[100, 50, 0], &phosphor.image(&palette)[..3]);
+    for _ in 0..8 {
+        phosphor.fade();
+    }
+    assert!(!phosphor.is_lit());
+}
+
+#[test]
+fn test_pacer_presents_once_per_frame() {
+    let mut pacer: Pacer = Default::default();
+    let mut presents = Vec::new();
+    // Frames drawing these images, mid-frame and then at the vertical blank.
+    let frames: [&[&[u8]]; 5] = [&[&[1], &[2]], &[&[2]], &[&[2]], &[&[3], &[4], &[5]], &[]];
+    for images in frames.iter() {
+        let mut count = 0;
+        for image in images.iter() {
+            if pacer.present(image) {
+                count += 1;
+            }
+        }
+        pacer.vblank();
+        presents.push(count);
+    }
+    // Only the first image of a frame shows straight away, and images
+    // already on screen are skipped.
+    assert_eq!(vec![1, 1, 0, 1, 0], presents);
+    assert!(pacer.present(&[5]));
+}
+
+#[test]
+fn test_frame_image_rows() {
+    let palette = Palette { colors: [[0, 0, 0], [255, 255, 255], [0, 0, 0], [0, 0, 0]] };
+    let mut gfx: Framebuffer = Default::default();
+    gfx.draw_sprite(63, 31, &[0x80], false);
+    let image = frame_image(&gfx, &palette);
+    assert_eq!(&[0, 0, 0], &image[..3]);
+    assert_eq!(&[255, 255, 255], &image[image.len() - 3..]);
+}
+
+#[test]
+fn test_screen_viewport() {
+    // exact fit
+    assert_eq!((0, 0, 640, 320), screen_viewport((640, 320), 0));
+    // whole number scale, centered
+    assert_eq!((5, 20, 640, 320), screen_viewport((650, 360), 0));
+    assert_eq!((69, 22, 512, 256), screen_viewport((650, 300), 10));
+    // never smaller than one window pixel a pixel
+    assert_eq!((0, 0, 64, 32), screen_viewport((40, 20), 8));
+    assert_eq!((288, 144, 64, 32), screen_viewport((640, 320), u32::MAX));
+}
+
+#[test]
+fn test_terminal_braille() {
+    let palette: Palette = Default::default();
+    let mut gfx: Framebuffer = Default::default();
+    
+    // top left pixel and bottom right pixel of the first cell
+    gfx.draw_sprite(0, 0, &[0x80], false);
+    gfx.draw_sprite(1, 3, &[0x80], false);
+    let lines = screen_lines(&gfx, &palette, Glyphs::Braille);
+    
+    assert_eq!(8, lines.len());
+    assert!(lines[0].contains('\u{2881}'));
+    assert!(lines[1].contains('\u{2800}'));
+}
+
+#[test]
+fn test_keymap_config() {
+    let config = parse_config("[keys]\n\
+                               5 = [\"W\", \"Up\"]\n\
+                               [keys.\"PONG.ch8\"]\n\
+                               layout = \"azerty\"\n\
+                               1 = \"Numpad1\"\n").unwrap();
+    
+    // global bindings replace the layout's binding for that key
+    let keymap = from_config(&config, "BRIX", None).unwrap();
+    assert_eq!(vec![0x5], keymap.keys_for("up"));
+    assert_eq!(vec![0x5], keymap.keys_for("W"));
+    assert_eq!(vec![0x4], keymap.keys_for("Q"));
+    
+    // rom sections can change the layout and add bindings on top
+    let keymap = from_config(&config, "PONG.ch8", None).unwrap();
+    assert_eq!(vec![0x4], keymap.keys_for("A"));
+    assert_eq!(vec![0x1], keymap.keys_for("Numpad1"));
+    assert!(keymap.keys_for("Key1").is_empty());
+    
+    // a layout from the command line wins over the rom section
+    let keymap = from_config(&config, "PONG.ch8", Some("keypad")).unwrap();
+    assert_eq!(vec![0xD], keymap.keys_for("Add"));
+    assert_eq!("keypad", layout_name(&config, "PONG.ch8", Some("keypad")).unwrap());
+    assert_eq!("azerty", layout_name(&config, "PONG.ch8", None).unwrap());
+    assert_eq!("qwerty", layout_name(&config, "BRIX", None).unwrap());
+    
+    assert!(from_config(&config, "BRIX", Some("dvorak")).is_err());
+    
+    // misspelt keys are errors rather than leaving the key unbound
+    let config = parse_config("[keys]\n5 = [\"W\", \"Upp\"]\n[gamepad]\n6 = \"Soutth\"\n").unwrap();
+    assert!(from_config(&config, "BRIX", None).unwrap_err().contains("'Upp'"));
+    assert!(gamepad_from_config(&config).unwrap_err().contains("'Soutth'"));
+}
+
+#[test]
+fn test_keyboard_multiple_host_keys() {
+    let mut keymap: Keymap = Default::default();
+    keymap.bind(0x5, &["W".to_string(), "Up".to_string()]);
+    let mut keyboard: Keyboard = Default::default();
+    let mut key_press = vec![0u8; 16];
+    
+    keyboard.press(&keymap, "W", &mut key_press);
+    keyboard.press(&keymap, "Up", &mut key_press);
+    keyboard.release(&keymap, "W", &mut key_press);
+    
+    // still held by the other key
+    assert_eq!(1, key_press[0x5]);
+    
+    keyboard.release(&keymap, "Up", &mut key_press);
+    assert_eq!(0, key_press[0x5]);
+    
+    assert_eq!(vec![0xA], keymap.keys_for(&key_name_for_char(b'Z').unwrap()));
+    assert!(keymap.keys_for(&key_name_for_char(b'p').unwrap()).is_empty());
+}
+
+#[test]
+fn test_key_name_for_char() {
+    assert_eq!(Some("z".to_string()), key_name_for_char(b'Z'));
+    assert_eq!(Some("1".to_string()), key_name_for_char(b'1'));
+    assert_eq!(None, key_name_for_char(b' '));
+    assert_eq!(None, key_name_for_char(0x1B));
+}
+
+#[test]
+fn test_opcode_fx0a() {
+    let mut state: Chip8State = Default::default(); 
+    let mut memory = vec![0u8; 4096];
+    init_state(&mut state);
+    
+    // no key, so wait on the same instruction
+    execute_opcode(0xF30A, &mut state, &mut memory);
+    assert_eq!(0x200, state.pc);
+    assert!(state.waiting_for_key);
+    
+    // a key going down is not enough
+    state.key_press[0xB] = 1;
+    execute_opcode(0xF30A, &mut state, &mut memory);
+    assert_eq!(0x200, state.pc);
+    
+    // it also has to come back up
+    state.key_press[0xB] = 0;
+    execute_opcode(0xF30A, &mut state, &mut memory);
+    assert_eq!(0x202, state.pc);
+    assert_eq!(0xB, state.v[3]);
+    assert!(!state.waiting_for_key);
+}
+
+#[test]
+fn test_opcode_fx0a_on_press_quirk() {
+    let mut state: Chip8State = Default::default(); 
+    let mut memory = vec![0u8; 4096];
+    init_state(&mut state);
+    state.quirks.key_wait_on_press = true;
+    
+    // a held key completes straight away, every time
+    state.key_press[0x4] = 1;
+    execute_opcode(0xF30A, &mut state, &mut memory);
+    execute_opcode(0xF50A, &mut state, &mut memory);
+    assert_eq!(0x204, state.pc);
+    assert_eq!(0x4, state.v[3]);
+    assert_eq!(0x4, state.v[5]);
+}
+
+#[test]
+fn test_opcode_cxnn_seeded() {
+    let mut state: Chip8State = Default::default(); 
+    let mut memory = vec![0u8; 4096];
+    init_state(&mut state);
+    
+    // the same seed gives the same numbers
+    state.rng = 1234;
+    execute_opcode(0xC0FF, &mut state, &mut memory);
+    execute_opcode(0xC10F, &mut state, &mut memory);
+    let first = (state.v[0], state.v[1]);
+    
+    state.rng = 1234;
+    execute_opcode(0xC0FF, &mut state, &mut memory);
+    execute_opcode(0xC10F, &mut state, &mut memory);
+    assert_eq!(first, (state.v[0], state.v[1]));
+    
+    // and the mask is applied
+    assert_eq!(0, state.v[1] & 0xF0);
+}
+
+#[test]
+fn test_movie_playback() {
+    let movie = parse_movie("chip8-movie 1\n\
+                             rom 00000000000000ff\n\
+                             seed 0000002a\n\
+                             cycles_per_frame 10\n\
+                             quirk key_wait_on_press true\n\
+                             k 0 0000\n\
+                             k 2 0021\n\
+                             c 3 1234\n\
+                             e 5\n").unwrap();
+    assert_eq!(0xff, movie.rom_hash);
+    assert_eq!(42, movie.seed);
+    assert!(movie.quirks.key_wait_on_press);
+    assert_eq!(5, movie.length());
+    
+    let mut player = Player::new(movie, true);
+    let mut key_press = vec![1u8; 16];
+    player.play_keys(0, &mut key_press);
+    assert_eq!(0, key_mask(&key_press));
+    player.play_keys(2, &mut key_press);
+    assert_eq!(1, key_press[0x0]);
+    assert_eq!(1, key_press[0x5]);
+    assert_eq!(0x21, key_mask(&key_press));
+    
+    // a framebuffer that does not match the checkpoint is reported
+    assert!(player.end_frame(2, &Default::default()).is_ok());
+    assert!(player.end_frame(3, &Default::default()).is_err());
+    assert!(player.finished(5));
+}
+
+#[test]
+fn test_input_script() {
+    let mut script = parse_script("# frame keys\n\
+                                   0 -\n\
+                                   60 5\n\
+                                   64 4 6\n\
+                                   70 -\n").unwrap();
+    assert_eq!(0, script.poll(59));
+    assert_eq!(1 << 5, script.poll(60));
+    assert_eq!(1 << 5, script.poll(63));
+    assert_eq!((1 << 4) | (1 << 6), script.poll(64));
+    assert_eq!(0, script.poll(100));
+    
+    assert!(parse_script("1 G").is_err());
+    assert!(parse_script("10 1\n5 2").is_err());
+}
+
+#[test]
+fn test_inputs_merge() {
+    let mut inputs: Inputs = Default::default();
+    inputs.add(Box::new(parse_script("0 1").unwrap()));
+    inputs.add(Box::new(parse_script("0 2\n3 -").unwrap()));
+    let mut key_press = vec![0u8; 16];
+    inputs.poll(0, &mut key_press);
+    assert_eq!(0x6, key_mask(&key_press));
+    
+    // a key released by one source stays down while another holds it
+    inputs.poll(3, &mut key_press);
+    assert_eq!(0x2, key_mask(&key_press));
+}
+
+#[test]
+fn test_disassemble() {
+    assert_eq!(Instruction::Draw(0, 1, 5), decode(0xD015));
+    assert_eq!(Class::Draw, decode(0xD015).class());
+    assert_eq!("DRW V0, V1, 5", disassemble(0xD015));
+    assert_eq!("LD VA, #02", disassemble(0x6A02));
+    assert_eq!("JP #202", disassemble(0x1202));
+    assert_eq!("SHL V3, V4", disassemble(0x834E));
+    assert_eq!("LD V5, [I]", disassemble(0xF565));
+    assert_eq!("DW #5121", disassemble(0x5121));
+    assert_eq!(Class::Unknown, decode(0xE1FF).class());
+}
+
+#[test]
+fn test_trace_filter() {
+    let filter = TraceFilter {
+        pc_range: Some(parse_pc_range("0x200-0x20F").unwrap()),
+        classes: parse_classes("draw, key").unwrap()
+    };
+    assert!(filter.matches(0x208, 0xD015));
+    assert!(filter.matches(0x20E, 0xF00A));
+    assert!(!filter.matches(0x210, 0xD015));
+    assert!(!filter.matches(0x208, 0x7001));
+    assert!(TraceFilter::default().matches(0xFFE, 0x7001));
+    
+    assert!(parse_pc_range("0x300-0x200").is_err());
+    assert!(parse_classes("draw,sound").is_err());
+}
+
+#[test]
+fn test_trace_diff() {
+    let a = parse_text_trace("1          200 6A02 LD VA, #02       VA=02 I=000 VF=00\n\
+                              2          202 A300 LD I, #300       I=300 VF=00\n\
+                              3          204 7A01 ADD VA, #01      VA=03 I=300 VF=00\n").unwrap();
+    assert_eq!(3, a.len());
+    assert_eq!(0x202, a[1].pc);
+    assert_eq!(0x300, a[1].index);
+    // registers carry over from earlier lines
+    assert_eq!(2, a[1].v[0xA]);
+    assert_eq!(3, a[2].v[0xA]);
+    
+    let mut b = a.clone();
+    assert_eq!(None, first_difference(&a, &b));
+    b[2].v[0xA] = 4;
+    assert_eq!(Some(2), first_difference(&a, &b));
+    assert_eq!(Some(2), first_difference(&a, &a[..2]));
+}
+
+#[test]
+fn test_gdb_packets() {
+    let mut state: Chip8State = Default::default(); 
+    let mut memory = vec![0u8; 4096];
+    init_state(&mut state);
+    let mut breakpoints = Vec::new();
+    let debug_info = Default::default();
+    
+    assert_eq!("$OK#9a", packet("OK"));
+    
+    // V0 to VF, then I, PC (little endian), SP, DT and ST
+    state.v[1] = 0xAB;
+    state.index = 0x123;
+    assert_eq!(Action::Reply(format!("00ab{}23010002000000", "00".repeat(14))),
+               handle_packet("g", &mut breakpoints, &mut state, &mut memory, &debug_info));
+    assert_eq!(Action::Reply("0002".to_string()), handle_packet("p11", &mut breakpoints, &mut state, &mut memory, &debug_info));
+    assert_eq!(Action::Reply("OK".to_string()), handle_packet("P11=0403", &mut breakpoints, &mut state, &mut memory, &debug_info));
+    assert_eq!(0x304, state.pc);
+    
+    assert_eq!(Action::Reply("OK".to_string()), handle_packet("M300,2:abcd", &mut breakpoints, &mut state, &mut memory, &debug_info));
+    assert_eq!(Action::Reply("abcd".to_string()), handle_packet("m300,2", &mut breakpoints, &mut state, &mut memory, &debug_info));
+    assert_eq!(Action::Reply("E01".to_string()), handle_packet("mfff,2", &mut breakpoints, &mut state, &mut memory, &debug_info));
+    assert_eq!(Action::Reply("E01".to_string()), handle_packet("mffffffffffffffff,2", &mut breakpoints, &mut state, &mut memory, &debug_info));
+    assert_eq!(Action::Reply("E01".to_string()), handle_packet("Mffffffffffffffff,1:00", &mut breakpoints, &mut state, &mut memory, &debug_info));
+    assert_eq!(Action::Reply("E01".to_string()), handle_packet("M300,1:\u{e9}0", &mut breakpoints, &mut state, &mut memory, &debug_info));
+    assert_eq!(Action::Reply("E01".to_string()), handle_packet("qRcmd,\u{e9}0", &mut breakpoints, &mut state, &mut memory, &debug_info));
+    
+    let xml = match handle_packet("qXfer:features:read:target.xml:0,ffff", &mut breakpoints, &mut state, &mut memory, &debug_info) {
+        Action::Reply(reply) => reply,
+        action => panic!("{:?}", action)
+    };
+    assert!(xml.starts_with("l<?xml"));
+    assert_eq!(Action::Reply(format!("m{}", &xml[2..4])),
+               handle_packet("qXfer:features:read:target.xml:1,2", &mut breakpoints, &mut state, &mut memory, &debug_info));
+    assert_eq!(Action::Reply(format!("l{}", &xml[2..])),
+               handle_packet("qXfer:features:read:target.xml:1,ffffffffffffffff", &mut breakpoints, &mut state, &mut memory, &debug_info));
+    assert_eq!(Action::Reply("l".to_string()),
+               handle_packet("qXfer:features:read:target.xml:ffffff,10", &mut breakpoints, &mut state, &mut memory, &debug_info));
+    
+    handle_packet("Z0,208,2", &mut breakpoints, &mut state, &mut memory, &debug_info);
+    assert_eq!(vec![0x208], breakpoints);
+    handle_packet("z0,208,2", &mut breakpoints, &mut state, &mut memory, &debug_info);
+    assert!(breakpoints.is_empty());
+    
+    assert_eq!(Action::Step, handle_packet("s", &mut breakpoints, &mut state, &mut memory, &debug_info));
+    assert_eq!(Action::Continue, handle_packet("c200", &mut breakpoints, &mut state, &mut memory, &debug_info));
+    assert_eq!(0x200, state.pc);
+}
+
+#[test]
+fn test_json() {
+    let value = parse_json("{\"seq\": 3, \"command\": \"next\", \"arguments\": {\"threadId\": 1, \"x\": [true, null, \"a\\\"b\"]}}").unwrap();
+    assert_eq!(Some(3), value.get("seq").as_i64());
+    assert_eq!(Some("next"), value.get("command").as_str());
+    assert_eq!(Some(1), value.get("arguments").get("threadId").as_i64());
+    assert_eq!(3, value.get("arguments").get("x").as_array().len());
+    assert_eq!(&Json::Null, value.get("missing"));
+    // written back out in the same order
+    assert_eq!("{\"seq\":3,\"command\":\"next\",\"arguments\":{\"threadId\":1,\"x\":[true,null,\"a\\\"b\"]}}",
+               value.to_string());
+    assert!(parse_json("{\"a\": }").is_err());
+}
+
+#[test]
+fn test_base64() {
+    assert_eq!("AOAiCA==", base64_encode(&[0x00, 0xE0, 0x22, 0x08]));
+    assert_eq!("q80=", base64_encode(&[0xAB, 0xCD]));
+    assert_eq!(Some(vec![0xAB, 0xCD, 0xEF]), base64_decode("q83v"));
+    assert_eq!(Some(vec![0x00, 0xE0, 0x22, 0x08]), base64_decode("AOAiCA=="));
+}
+
+#[test]
+fn test_dap_memory_requests() {
+    let memory = vec![0x12u8; 4096];
+    let debug_info = Default::default();
+    let read = |json: &str| dap::read_memory(&parse_json(json).unwrap(), &memory);
+    
+    let response = read("{\"memoryReference\": \"0xFFE\", \"count\": 9223372036854775807}").unwrap();
+    assert_eq!(Some("EhI="), response.get("data").as_str());
+    assert!(response.get("unreadableBytes").as_i64().unwrap() > 0);
+    let response = read("{\"memoryReference\": \"0x200\", \"count\": -5}").unwrap();
+    assert_eq!(Some(0), response.get("unreadableBytes").as_i64());
+    assert!(read("{\"memoryReference\": \"0x200\", \"offset\": 9223372036854775807}").is_err());
+    
+    let disassemble = |json: &str| dap::disassemble(&parse_json(json).unwrap(), &memory, &debug_info);
+    let response = disassemble("{\"memoryReference\": \"0x200\", \"offset\": 9223372036854775807, \
+                                 \"instructionOffset\": 9223372036854775807, \"instructionCount\": 2}");
+    assert_eq!(2, response.get("instructions").as_array().len());
+    assert_eq!(Some("??"), response.get("instructions").as_array()[1].get("instruction").as_str());
+    let response = disassemble("{\"memoryReference\": \"0xFFC\", \"instructionCount\": 9223372036854775807}");
+    let instructions = response.get("instructions").as_array();
+    assert_eq!(0x1000, instructions.len());
+    assert_eq!(Some("0xFFC"), instructions[0].get("address").as_str());
+    assert_eq!(Some("JP #212"), instructions[1].get("instruction").as_str());
+    assert_eq!(Some("??"), instructions[2].get("instruction").as_str());
+}
+
+#[test]
+fn test_debug_info_lines() {
+    let info = parse_debug_info("line 0x200 game.8o 1\n\
+                                 line 0x202 game.8o 2\n\
+                                 line 0x208 game.8o 6\n", ::std::path::Path::new("/roms")).unwrap();
+    assert_eq!(Some((0x202, 2)), info.address_for_line("/roms/game.8o", 2));
+    // a line without code moves to the next one that has some
+    assert_eq!(Some((0x208, 6)), info.address_for_line("game.8o", 4));
+    assert_eq!(None, info.address_for_line("other.8o", 1));
+    assert_eq!(Some(("/roms/game.8o", 6)), info.line_for_address(0x208));
+    assert_eq!(None, info.line_for_address(0x204));
+}
+
+#[test]
+fn test_debug_info_labels() {
+    let info = parse_debug_info("label 0x200 main\n\
+                                 label 0x208 draw_player\n", ::std::path::Path::new("")).unwrap();
+    assert_eq!("main", info.describe(0x200));
+    assert_eq!("draw_player+4", info.describe(0x20C));
+    assert_eq!(Some(0x20C), info.resolve("draw_player+4"));
+    assert_eq!(Some(0x300), info.resolve("0x300"));
+    assert_eq!(None, info.resolve("missing"));
+    assert_eq!("CALL draw_player", disassemble_with_labels(0x2208, &info));
+    assert_eq!("JP #20A", disassemble_with_labels(0x120A, &info));
+}
+
+#[test]
+fn test_profiler() {
+    let mut state: Chip8State = Default::default();
+    let mut memory = vec![0u8; 4096];
+    init_state(&mut state);
+    // CALL 0x206, JP 0x202; at 0x206 LD V0, 5 and RET.
+    memory[0x200..0x208].copy_from_slice(&[0x22, 0x06, 0x12, 0x00, 0x00, 0x00, 0x60, 0x05]);
+    memory[0x208..0x20A].copy_from_slice(&[0x00, 0xEE]);
+    let info = parse_debug_info("label 0x206 set_v0\n", ::std::path::Path::new("")).unwrap();
+    let path = ::std::env::temp_dir().join("rust_chip8_test_profile.folded");
+    let mut profiler = Profiler::create(path.to_str().unwrap(), info).unwrap();
+    for _ in 0..8 {
+        profiler.before(&mut state, &mut memory);
+        emulate_cycle(&mut state, &mut memory);
+        profiler.after(&state, &memory);
+    }
+    assert_eq!(vec!["main 4".to_string(), "main;set_v0 4".to_string()], profiler.folded());
+
+    let counts = subroutine_counts("main 10\nmain;a 5\nmain;a;b 3\nmain;a;b;a 2\n").unwrap();
+    assert_eq!(SubroutineCounts { name: "main".to_string(), inclusive: 20, exclusive: 10 }, counts[0]);
+    assert_eq!(SubroutineCounts { name: "a".to_string(), inclusive: 10, exclusive: 7 }, counts[1]);
+    assert_eq!(SubroutineCounts { name: "b".to_string(), inclusive: 5, exclusive: 3 }, counts[2]);
+    assert!(subroutine_counts("main;a\n").is_err());
+}
+
+#[test]
+fn test_recorder_writes_keys_straight_away() {
+    let path = ::std::env::temp_dir().join("rust_chip8_test_recorder.movie");
+    let movie = Movie { cycles_per_frame: 10, ..Default::default() };
+    let mut recorder = Recorder::create(path.to_str().unwrap(), &movie).unwrap();
+    let mut key_press = vec![0u8; 16];
+    recorder.record_keys(0, &key_press);
+    key_press[0x5] = 1;
+    recorder.record_keys(3, &key_press);
+    
+    // readable before the recorder is dropped, as when the process is killed
+    let text = ::std::fs::read_to_string(&path).unwrap();
+    let movie = parse_movie(&text).unwrap();
+    assert_eq!(vec![(0, 0x0000), (3, 0x0020)], movie.key_changes);
+    assert_eq!(4, movie.length());
+}
+
+#[test]
+fn test_coverage_listing() {
+    let mut state: Chip8State = Default::default();
+    let mut memory = vec![0u8; 4096];
+    init_state(&mut state);
+    // LD I, 0x20A; LD B, V0; JP 0x204; a skip that never runs; 3 bytes of data.
+    let rom = [0xA2, 0x0A, 0xF0, 0x33, 0x12, 0x04, 0x30, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00];
+    memory[0x200..0x200 + rom.len()].copy_from_slice(&rom);
+    state.v[0] = 123;
+    let mut coverage = Coverage::create(None, None, &rom, Default::default()).unwrap();
+    for _ in 0..5 {
+        coverage.before(&mut state, &mut memory);
+        emulate_cycle(&mut state, &mut memory);
+        coverage.after(&state, &memory);
+    }
+    let listing = coverage.listing();
+    let lines: Vec<&str> = listing.lines().collect();
+    assert_eq!("; 13 bytes: 6 run as code (46.2%), 3 used as data (23.1%), 4 untouched", lines[0]);
+    assert_eq!("  202   F033           1                    LD B, V0", lines[3]);
+    assert_eq!("  204   1204           3                    JP #204", lines[4]);
+    assert_eq!("  206   3000           -                    SE V0, #00", lines[5]);
+    assert_eq!("  20A   01                               1  DB #01", lines[7]);
+    assert_eq!("  20C   03                               1  DB #03", lines[9]);
+    assert_eq!(512 * 512 * 3 + 15, coverage.heatmap().len());
+    
+    // a pc off the end of memory is left for the interpreter to report
+    state.pc = 0xFFF;
+    coverage.before(&mut state, &mut memory);
+    coverage.after(&state, &memory);
+}
+
+#[test]
+fn test_analysis() {
+    // A skip over a jump, sprite data, then a store over the jump it skipped.
+    let rom = [0x60, 0x00, 0x30, 0x01, 0x12, 0x0A, 0x12, 0x0E, 0xF0, 0x90,
+               0xA2, 0x06, 0xF0, 0x55, 0x12, 0x0E];
+    let analysis = analyze(&rom);
+    let starts: Vec<usize> = analysis.blocks.keys().cloned().collect();
+    assert_eq!(vec![0x200, 0x204, 0x206, 0x20A, 0x20E], starts);
+    assert_eq!(Exit::Skip { next: 0x204, skipped: 0x206 }, analysis.blocks[&0x200].exit);
+    assert_eq!(Exit::Fallthrough(0x20E), analysis.blocks[&0x20A].exit);
+    assert_eq!(Exit::Jump(0x20E), analysis.blocks[&0x20E].exit);
+    assert!(analysis.is_code(0x207));
+    assert!(!analysis.is_code(0x208) && !analysis.is_code(0x209));
+    assert_eq!(vec![Note::SelfModifying(0x20C, 0x206)], analysis.notes);
+
+    let dot = analysis.dot(&Default::default());
+    assert!(dot.contains("b200 -> b206 [label=\"skip\"];"));
+    assert!(dot.contains("b20A -> b20E;"));
+    
+    // A call that moves I away from the code before a store.
+    let rom = [0xA2, 0x00, 0x22, 0x08, 0xF0, 0x55, 0x12, 0x06, 0xA3, 0x00, 0x00, 0xEE];
+    assert_eq!(vec![Note::UnknownStore(0x204)], analyze(&rom).notes);
+}
+
+#[test]
+fn test_decompile() {
+    let rom = [0x60, 0x00, 0xA2, 0x22,
+               // A loop drawing until V0 is 64.
+               0xD0, 0x11, 0x70, 0x01, 0x40, 0x40, 0x12, 0x0E, 0x12, 0x04,
+               // A skip over one instruction, then over a jump with an else.
+               0x61, 0x05, 0x31, 0x05, 0x62, 0x01,
+               0x32, 0x00, 0x12, 0x1C, 0x63, 0x03, 0x12, 0x20, 0x63, 0x04, 0x63, 0x05,
+               0x12, 0x20, 0xF0, 0x90, 0x90, 0xF0];
+    let expected = "# Decompiled by rust_chip8\n\
+                    \n\
+                    : main\n\
+                    \x20 v0 := 0\n\
+                    \x20 i := data_222\n\
+                    \x20 loop\n\
+                    \x20   sprite v0 v1 1\n\
+                    \x20   v0 += 1\n\
+                    \x20   while v0 != 0x40\n\
+                    \x20 again\n\
+                    \x20 v1 := 5\n\
+                    \x20 if v1 != 5 then v2 := 1\n\
+                    \x20 if v2 == 0 begin\n\
+                    \x20   v3 := 3\n\
+                    \x20 else\n\
+                    \x20   v3 := 4\n\
+                    \x20   v3 := 5\n\
+                    \x20 end\n\
+                    \x20 loop\n\
+                    \x20 again\n\
+                    \n\
+                    : data_222\n\
+                    \x20 0xF0 0x90 0x90 0xF0\n";
+    assert_eq!(expected, decompile(&analyze(&rom), &Default::default()));
+}
+
+#[test]
+fn test_recompile() {
+    // V0 = 5, then call a subroutine adding 1 to it forever.
+    let rom = [0x60, 0x05, 0x22, 0x08, 0x12, 0x02, 0x00, 0x00, 0x70, 0x01, 0x00, 0xEE];
+    let code = recompile(&rom, "test.ch8");
+    // A segment ends at each call, so the return lands at the start of one.
+    assert!(code.contains("0x202 if unchanged(memory, ROM, 0x202, 0x204) => segment_202(state, memory, budget),"));
+    assert!(code.contains("0x204 if unchanged(memory, ROM, 0x204, 0x206) => segment_204(state, memory, budget),"));
+    assert!(code.contains("0x208 if unchanged(memory, ROM, 0x208, 0x20C) => segment_208(state, memory, budget),"));
+    assert!(code.contains("    state.v[0] = 0x05;\n\
+                           \x20   if spent(state, budget) {\n\
+                           \x20       state.pc = 0x202;\n\
+                           \x20       return false;\n\
+                           \x20   }\n\
+                           \x20   state.pc = 0x202;\n\
+                           \x20   false\n"));
+    assert!(code.contains("    state.stack[state.stack_pointer] = 0x202;\n\
+                           \x20   state.stack_pointer += 1;\n\
+                           \x20   state.pc = 0x208;\n"));
+    assert!(code.contains("    state.pc = state.stack[state.stack_pointer] + 2;\n"));
+    
+    // A store through an I the analysis can't follow may write over the
+    // rest of its block, so the segment ends there and the rest is checked
+    // again before it runs.
+    let rom = [0xA2, 0x0A, 0xF0, 0x1E, 0xF1, 0x55, 0x70, 0x01, 0x12, 0x00];
+    assert_eq!(vec![Segment { start: 0x200, end: 0x206 }, Segment { start: 0x206, end: 0x20A }],
+               segments(&analyze(&rom)));
+    let code = recompile(&rom, "test.ch8");
+    assert!(code.contains("    let drawn = interpret(state, memory, 0x204, 0xF155);\n\
+                           \x20   spent(state, budget);\n\
+                           \x20   drawn\n}\n"));
+}
+
+#[test]
+fn test_engines() {
+    // A loop calling a subroutine that clears the screen, then storing V0
+    // over its first instruction, turning V1 = 1 into V1 += 1.
+    let rom = [0xA2, 0x08, 0x60, 0x71, 0x63, 0x00, 0x22, 0x12, 0x61, 0x01, 0x73, 0x01,
+               0xF0, 0x55, 0x12, 0x06, 0x00, 0x00, 0x72, 0x01, 0x00, 0xE0, 0x00, 0xEE];
+    let run = |rom: &[u8], engine: Engine| {
+        let mut state: Chip8State = Default::default();
+        init_state(&mut state);
+        let mut memory = vec![0u8; 4096];
+        memory[0x200..0x200 + rom.len()].copy_from_slice(rom);
+        let mut monitors: Monitors = Default::default();
+        monitors.set_engine(engine);
+        let mut frames = Vec::new();
+        for _ in 0..30 {
+            let mut draws = 0;
+            monitors.run(&mut state, &mut memory, 7, |_| draws += 1);
+            frames.push((format!("{:?}", state), draws));
+        }
+        frames
+    };
+    let cached = run(&rom, Engine::Cached);
+    assert_eq!(run(&rom, Engine::Interpreter), cached);
+    assert_eq!(run(&rom, Engine::Predecoded), cached);
+    // The store was seen, so V1 counts up rather than staying at 1.
+    assert!(cached.last().unwrap().0.contains("v: [113, 26, 26, 26,"));
+
+    // The interpreter takes 5XY1 for a skip, though the decoder doesn't.
+    let rom = [0x60, 0x05, 0x61, 0x05, 0x50, 0x11, 0x62, 0x01, 0x63, 0x01, 0x12, 0x0A];
+    let cached = run(&rom, Engine::Cached);
+    assert_eq!(run(&rom, Engine::Interpreter), cached);
+    assert_eq!(run(&rom, Engine::Predecoded), cached);
+    assert!(cached.last().unwrap().0.contains("v: [5, 5, 0, 1,"));
+}
+
+#[test]
+#[should_panic]
+fn test_cached_engine_end_of_memory() {
+    // Fails like the interpreter rather than looping forever.
+    let mut state: Chip8State = Default::default();
+    init_state(&mut state);
+    state.pc = 0xFFF;
+    let mut memory = vec![0u8; 4096];
+    let mut monitors: Monitors = Default::default();
+    monitors.set_engine(Engine::Cached);
+    monitors.run(&mut state, &mut memory, 1, |_| ());
+}
+
+#[test]
+fn test_bench_workloads() {
+    for &(name, rom) in WORKLOADS.iter() {
+        let state = bench::run(rom, Engine::Interpreter, 20_000);
+        assert_eq!(20_000, state.cycles, "{}", name);
+        for &engine in [Engine::Predecoded, Engine::Cached].iter() {
+            assert_eq!(format!("{:?}", state), format!("{:?}", bench::run(rom, engine, 20_000)), "{}", name);
+        }
+    }
+    assert_eq!((2.0, 1.0, 5.0), summarize(&[5.0, 1.0, 2.0]));
+    assert_eq!((2.5, 1.0, 5.0), summarize(&[5.0, 1.0, 2.0, 3.0]));
+}
+
+#[test]
+fn test_framebuffer() {
+    let mut gfx: Framebuffer = Default::default();
+    assert!(!gfx.draw_sprite(10, 5, &[0b1100_0001], false));
+    assert_eq!(1, gfx.pixel(10, 5));
+    assert_eq!(1, gfx.pixel(11, 5));
+    assert_eq!(0, gfx.pixel(12, 5));
+    assert_eq!(1, gfx.pixel(17, 5));
+    // Drawing over a lit pixel turns it off and reports a collision.
+    assert!(gfx.draw_sprite(11, 5, &[0x80], false));
+    assert_eq!(0, gfx.pixel(11, 5));
+    assert!(!gfx.draw_sprite(11, 5, &[0x80], false));
+
+    // Hashes match those of the screen as a byte a pixel.
+    gfx.clear();
+    gfx.draw_sprite(60, 0, &[0xFF], false);
+    let mut pixels = vec![0u8; 2048];
+    for pixel in &mut pixels[60..64] {
+        *pixel = 1;
+    }
+    assert_eq!(hash(&pixels), gfx.hash());
+}
+
+#[test]
+fn test_framebuffer_dirty_spans() {
+    let mut gfx: Framebuffer = Default::default();
+    // Nothing has been presented, so everything needs to be.
+    assert_eq!(32, gfx.take_dirty().len());
+    assert!(gfx.take_dirty().is_empty());
+
+    gfx.draw_sprite(10, 5, &[0b1000_0001], false);
+    gfx.draw_sprite(60, 7, &[0xF0], false);
+    gfx.draw_sprite(62, 8, &[0xC0], false);
+    assert_eq!(vec![Span { y: 5, x: 10, width: 8 }, Span { y: 7, x: 60, width: 4 },
+                    Span { y: 8, x: 62, width: 2 }], gfx.take_dirty());
+
+    // Clearing marks only the pixels that were lit.
+    gfx.clear();
+    assert_eq!(vec![Span { y: 5, x: 10, width: 8 }, Span { y: 7, x: 60, width: 4 },
+                    Span { y: 8, x: 62, width: 2 }], gfx.take_dirty());
+    gfx.clear();
+    assert!(gfx.take_dirty().is_empty());
+
+    // Whether a screen has been presented doesn't change what's on it.
+    let fresh: Framebuffer = Default::default();
+    assert_eq!(fresh, gfx);
+    assert_eq!(format!("{:?}", fresh), format!("{:?}", gfx));
+}
+
+// Draws an 8x4 block with DXYN at (x, y), returning the lit pixels.
+fn draw_block(x: u8, y: u8, wrap_sprites: bool) -> Vec<(usize, usize)> {
+    let mut state: Chip8State = Default::default();
+    let mut memory = vec![0u8; 4096];
+    init_state(&mut state);
+    state.quirks.wrap_sprites = wrap_sprites;
+    state.index = 0x300;
+    for row in 0..4 {
+        memory[0x300 + row] = 0xFF;
+    }
+    state.v[0] = x;
+    state.v[1] = y;
+    execute_opcode(0xD014, &mut state, &mut memory);
+    let mut lit = Vec::new();
+    for py in 0..32 {
+        for px in 0..64 {
+            if state.gfx.pixel(px, py) == 1 {
+                lit.push((px, py));
+            }
+        }
+    }
+    lit
+}
+
+#[test]
+fn test_opcode_dxyn_edges() {
+    let columns = |lit: &[(usize, usize)]| {
+        let mut xs: Vec<usize> = lit.iter().map(|&(x, _)| x).collect();
+        xs.sort();
+        xs.dedup();
+        xs
+    };
+    let rows = |lit: &[(usize, usize)]| {
+        let mut ys: Vec<usize> = lit.iter().map(|&(_, y)| y).collect();
+        ys.sort();
+        ys.dedup();
+        ys
+    };
+
+    // Right edge: cut off, or wrapped to the left on the same rows.
+    let lit = draw_block(60, 0, false);
+    assert_eq!(vec![60, 61, 62, 63], columns(&lit));
+    assert_eq!(vec![0, 1, 2, 3], rows(&lit));
+    let lit = draw_block(60, 0, true);
+    assert_eq!(vec![0, 1, 2, 3, 60, 61, 62, 63], columns(&lit));
+    assert_eq!(vec![0, 1, 2, 3], rows(&lit));
+
+    // Bottom edge: cut off, or wrapped to the top.
+    let lit = draw_block(0, 30, false);
+    assert_eq!(vec![30, 31], rows(&lit));
+    assert_eq!(16, lit.len());
+    let lit = draw_block(0, 30, true);
+    assert_eq!(vec![0, 1, 30, 31], rows(&lit));
+
+    // Left and top edges: starting coordinates off the screen wrap onto it,
+    // so 255 is the last column or row.
+    let lit = draw_block(255, 4, false);
+    assert_eq!(vec![63], columns(&lit));
+    let lit = draw_block(255, 4, true);
+    assert_eq!(vec![0, 1, 2, 3, 4, 5, 6, 63], columns(&lit));
+    let lit = draw_block(66, 255, false);
+    assert_eq!(vec![2, 3, 4, 5, 6, 7, 8, 9], columns(&lit));
+    assert_eq!(vec![31], rows(&lit));
+    let lit = draw_block(66, 255, true);
+    assert_eq!(vec![0, 1, 2, 31], rows(&lit));
+
+    // The corner wraps both ways at once.
+    let lit = draw_block(62, 30, true);
+    assert_eq!(vec![0, 1, 2, 3, 4, 5, 62, 63], columns(&lit));
+    assert_eq!(vec![0, 1, 30, 31], rows(&lit));
+    assert_eq!(32, lit.len());
+
+    // A sprite running off the end of memory stops there.
+    let mut state: Chip8State = Default::default();
+    let mut memory = vec![0u8; 4096];
+    init_state(&mut state);
+    memory[0xFFE] = 0xFF;
+    memory[0xFFF] = 0x80;
+    state.index = 0xFFE;
+    execute_opcode(0xD014, &mut state, &mut memory);
+    assert!((0..8).all(|x| state.gfx.pixel(x, 0) == 1));
+    assert_eq!(1, state.gfx.pixel(0, 1));
+    assert!((0..64).all(|x| state.gfx.pixel(x, 2) == 0 && state.gfx.pixel(x, 3) == 0));
+}
@@ -0,0 +1,361 @@
+use std::fs::File;
+use std::io::prelude::*;
+use std::io::BufWriter;
+
+use config::parse_number;
+use debuginfo::DebugInfo;
+use disasm;
+use disasm::Class;
+use monitor::Monitor;
+use {Chip8State, get_opcode};
+
+// Binary traces start with this, followed by one BINARY_RECORD_SIZE record
+// per instruction.
+pub const BINARY_MAGIC: &[u8; 8] = b"C8TRACE1";
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum TraceFormat {
+    // One line per instruction: the cycle, PC, opcode, disassembly, the V
+    // registers it changed, then I and VF. With labels from --debug-info the
+    // PC is also given as label+offset.
+    //
+    //   123        204 6A02 LD VA, #02       VA=02 I=300 VF=00
+    //   123        204 6A02 main+4           LD VA, #02       VA=02 I=300 VF=00
+    Text,
+    // Little endian records of the cycle (8 bytes), PC, opcode and I (2
+    // bytes each), V0 to VF, and a hash of the framebuffer (8 bytes), all
+    // taken after the instruction ran.
+    Binary
+}
+
+pub const BINARY_RECORD_SIZE: usize = 8 + 2 + 2 + 2 + 16 + 8;
+
+pub fn parse_format(name: &str) -> Result<TraceFormat, String> {
+    match name {
+        "text" => Ok(TraceFormat::Text),
+        "binary" => Ok(TraceFormat::Binary),
+        _ => Err(format!("Unknown trace format '{}', expected text or binary", name))
+    }
+}
+
+// Which instructions to trace. Empty classes trace every class.
+#[derive(Debug, Default, Clone, PartialEq)]
+pub struct TraceFilter {
+    pub pc_range: Option<(usize, usize)>,
+    pub classes: Vec<Class>
+}
+
+impl TraceFilter {
+    pub fn matches(&self, pc: usize, opcode: u16) -> bool {
+        if let Some((from, to)) = self.pc_range {
+            if pc < from || pc > to {
+                return false;
+            }
+        }
+        self.classes.is_empty() || self.classes.contains(&disasm::decode(opcode).class())
+    }
+}
+
+// An inclusive range of addresses such as "0x200-0x2FF".
+pub fn parse_pc_range(text: &str) -> Result<(usize, usize), String> {
+    let error = || format!("Invalid address range '{}', expected e.g. 0x200-0x2FF", text);
+    let mut parts = text.splitn(2, '-');
+    let from = parts.next().and_then(parse_number).ok_or_else(&error)?;
+    let to = parts.next().and_then(parse_number).ok_or_else(&error)?;
+    if from < 0 || to < from || to > 0xFFF {
+        return Err(error());
+    }
+    Ok((from as usize, to as usize))
+}
+
+// Comma separated class names such as "draw,key".
+pub fn parse_classes(text: &str) -> Result<Vec<Class>, String> {
+    let mut classes = Vec::new();
+    for name in text.split(',') {
+        let name = name.trim();
+        if !name.is_empty() {
+            classes.push(disasm::parse_class(name)?);
+        }
+    }
+    Ok(classes)
+}
+
+pub struct Tracer {
+    out: BufWriter<File>,
+    format: TraceFormat,
+    filter: TraceFilter,
+    debug_info: DebugInfo,
+    pc: usize,
+    opcode: u16,
+    v: [u8; 16],
+    gfx_hash: Option<u64>
+}
+
+impl Tracer {
+    pub fn create(path: &str, format: TraceFormat, filter: TraceFilter, debug_info: DebugInfo) -> Result<Tracer, String> {
+        let f = match File::create(path) {
+            Ok(f) => f,
+            Err(e) => return Err(format!("Could not create trace '{}': {}", path, e))
+        };
+        let mut out = BufWriter::new(f);
+        if format == TraceFormat::Binary {
+            out.write_all(BINARY_MAGIC).unwrap();
+        }
+        Ok(Tracer { out, format, filter, debug_info, pc: 0, opcode: 0, v: [0; 16], gfx_hash: None })
+    }
+
+    fn write_text(&mut self, state: &Chip8State) {
+        let mut changes = String::new();
+        for x in 0..0xF {
+            if state.v[x] != self.v[x] {
+                changes.push_str(&format!("V{:X}={:02X} ", x, state.v[x]));
+            }
+        }
+        let location = if self.debug_info.has_labels() {
+            format!("{:<16} ", self.debug_info.describe(self.pc))
+        } else {
+            String::new()
+        };
+        writeln!(self.out, "{:<10} {:03X} {:04X} {}{:<16} {}I={:03X} VF={:02X}",
+                 state.cycles, self.pc, self.opcode, location,
+                 disasm::disassemble_with_labels(self.opcode, &self.debug_info),
+                 changes, state.index, state.v[0xF]).unwrap();
+    }
+
+    fn write_binary(&mut self, state: &Chip8State) {
+        let mut record = Vec::with_capacity(BINARY_RECORD_SIZE);
+        record.extend_from_slice(&state.cycles.to_le_bytes());
+        record.extend_from_slice(&(self.pc as u16).to_le_bytes());
+        record.extend_from_slice(&self.opcode.to_le_bytes());
+        record.extend_from_slice(&state.index.to_le_bytes());
+        record.extend_from_slice(&state.v);
+        record.extend_from_slice(&self.gfx_hash.unwrap().to_le_bytes());
+        self.out.write_all(&record).unwrap();
+    }
+}
+
+impl Monitor for Tracer {
+    fn before(&mut self, state: &mut Chip8State, memory: &mut Vec<u8>) {
+        self.pc = state.pc;
+        self.opcode = get_opcode(state.pc, memory);
+        self.v.copy_from_slice(&state.v);
+    }
+
+    fn after(&mut self, state: &Chip8State, _memory: &[u8]) {
+        // Only drawing changes the screen, so avoid hashing it every time.
+        if self.format == TraceFormat::Binary &&
+            (self.gfx_hash.is_none() || disasm::decode(self.opcode).class() == Class::Draw) {
+            self.gfx_hash = Some(state.gfx.hash());
+        }
+        if !self.filter.matches(self.pc, self.opcode) {
+            return;
+        }
+        match self.format {
+            TraceFormat::Text => self.write_text(state),
+            TraceFormat::Binary => self.write_binary(state)
+        }
+    }
+
+    fn end_frame(&mut self) {
+        self.out.flush().unwrap();
+    }
+}
+
+// One instruction read back from a trace, with the machine state after it
+// ran. Text traces only show the registers an instruction changed, so the
+// rest are carried over from the lines before, and have no framebuffer hash.
+#[derive(Debug, Clone, PartialEq)]
+pub struct TraceRecord {
+    pub cycle: u64,
+    pub pc: usize,
+    pub opcode: u16,
+    pub index: u16,
+    pub v: [u8; 16],
+    pub gfx_hash: Option<u64>
+}
+
+pub fn parse_text_trace(text: &str) -> Result<Vec<TraceRecord>, String> {
+    let mut records = Vec::new();
+    let mut v = [0u8; 16];
+    for (line_number, line) in text.lines().enumerate() {
+        let fields: Vec<&str> = line.split_whitespace().collect();
+        if fields.is_empty() {
+            continue;
+        }
+        let error = || format!("Trace line {}: could not read '{}'", line_number + 1, line);
+        if fields.len() < 3 {
+            return Err(error());
+        }
+        let cycle = fields[0].parse().map_err(|_| error())?;
+        let pc = usize::from_str_radix(fields[1], 16).map_err(|_| error())?;
+        let opcode = u16::from_str_radix(fields[2], 16).map_err(|_| error())?;
+        let mut index = None;
+        for field in &fields[3..] {
+            let mut parts = field.splitn(2, '=');
+            let (name, value) = match (parts.next(), parts.next()) {
+                (Some(name), Some(value)) => (name, value),
+                _ => continue
+            };
+            if name == "I" {
+                index = Some(u16::from_str_radix(value, 16).map_err(|_| error())?);
+            } else if name.len() == 2 && name.starts_with('V') {
+                let x = usize::from_str_radix(&name[1..], 16).map_err(|_| error())?;
+                v[x] = u8::from_str_radix(value, 16).map_err(|_| error())?;
+            }
+        }
+        let index = index.ok_or_else(&error)?;
+        records.push(TraceRecord { cycle, pc, opcode, index, v, gfx_hash: None });
+    }
+    Ok(records)
+}
+
+pub fn parse_binary_trace(data: &[u8]) -> Result<Vec<TraceRecord>, String> {
+    if !data.starts_with(BINARY_MAGIC) || (data.len() - BINARY_MAGIC.len()) % BINARY_RECORD_SIZE != 0 {
+        return Err("Not a binary trace, or the trace is truncated".to_string());
+    }
+    let u16_at = |bytes: &[u8], at: usize| u16::from(bytes[at]) | u16::from(bytes[at + 1]) << 8;
+    let u64_at = |bytes: &[u8], at: usize| (0..8).fold(0u64, |value, i| value | u64::from(bytes[at + i]) << (8 * i));
+    let mut records = Vec::new();
+    for bytes in data[BINARY_MAGIC.len()..].chunks(BINARY_RECORD_SIZE) {
+        let mut v = [0u8; 16];
+        v.copy_from_slice(&bytes[14..30]);
+        records.push(TraceRecord {
+            cycle: u64_at(bytes, 0),
+            pc: u16_at(bytes, 8) as usize,
+            opcode: u16_at(bytes, 10),
+            index: u16_at(bytes, 12),
+            v,
+            gfx_hash: Some(u64_at(bytes, 30))
+        });
+    }
+    Ok(records)
+}
+
+// Reads a trace in either format.
+pub fn load_trace(path: &str) -> Result<Vec<TraceRecord>, String> {
+    let mut data = Vec::new();
+    match File::open(path) {
+        Ok(mut f) => {
+            if let Err(e) = f.read_to_end(&mut data) {
+                return Err(format!("Could not read trace '{}': {}", path, e));
+            }
+        }
+        Err(e) => return Err(format!("Could not open trace '{}': {}", path, e))
+    }
+    let result = if data.starts_with(BINARY_MAGIC) {
+        parse_binary_trace(&data)
+    } else {
+        parse_text_trace(&String::from_utf8_lossy(&data))
+    };
+    result.map_err(|e| format!("{}: {}", path, e))
+}
+
+// What differs between two records, e.g. ["PC 204 / 206", "V3 0A / 0B"].
+// The framebuffer is only compared when both traces have its hash.
+pub fn record_differences(a: &TraceRecord, b: &TraceRecord) -> Vec<String> {
+    let mut differences = Vec::new();
+    if a.cycle != b.cycle {
+        differences.push(format!("cycle {} / {}", a.cycle, b.cycle));
+    }
+    if a.pc != b.pc {
+        differences.push(format!("PC {:03X} / {:03X}", a.pc, b.pc));
+    }
+    if a.opcode != b.opcode {
+        differences.push(format!("opcode {:04X} / {:04X}", a.opcode, b.opcode));
+    }
+    for x in 0..16 {
+        if a.v[x] != b.v[x] {
+            differences.push(format!("V{:X} {:02X} / {:02X}", x, a.v[x], b.v[x]));
+        }
+    }
+    if a.index != b.index {
+        differences.push(format!("I {:03X} / {:03X}", a.index, b.index));
+    }
+    if let (Some(a_hash), Some(b_hash)) = (a.gfx_hash, b.gfx_hash) {
+        if a_hash != b_hash {
+            differences.push("framebuffer".to_string());
+        }
+    }
+    differences
+}
+
+// The position of the first record that differs, or where the shorter
+// trace ends, or None if the traces are the same.
+pub fn first_difference(a: &[TraceRecord], b: &[TraceRecord]) -> Option<usize> {
+    for (i, (a_record, b_record)) in a.iter().zip(b.iter()).enumerate() {
+        if !record_differences(a_record, b_record).is_empty() {
+            return Some(i);
+        }
+    }
+    if a.len() != b.len() {
+        Some(::std::cmp::min(a.len(), b.len()))
+    } else {
+        None
+    }
+}
+
+fn record_line(record: Option<&TraceRecord>) -> String {
+    match record {
+        Some(record) => format!("{:<10} {:03X} {:04X} {}", record.cycle, record.pc, record.opcode,
+                                disasm::disassemble(record.opcode)),
+        None => String::new()
+    }
+}
+
+// Describes the first difference between two traces, with `context`
+// instructions either side of it from both traces.
+pub fn diff_report(a_name: &str, a: &[TraceRecord], b_name: &str, b: &[TraceRecord], context: usize) -> Option<String> {
+    let at = first_difference(a, b)?;
+    let mut report = if at == a.len() || at == b.len() {
+        let (name, length) = if at == a.len() { (a_name, a.len()) } else { (b_name, b.len()) };
+        format!("{} ends after {} instructions\n", name, length)
+    } else {
+        format!("Traces differ at instruction {} (cycle {}): {}\n",
+                at + 1, a[at].cycle, record_differences(&a[at], &b[at]).join(", "))
+    };
+    report.push_str(&format!("\n  {:<40} {}\n", a_name, b_name));
+    let from = at.saturating_sub(context);
+    let to = ::std::cmp::min(at + context + 1, ::std::cmp::max(a.len(), b.len()));
+    for i in from..to {
+        let marker = if i == at { '>' } else { ' ' };
+        let line = format!("{} {:<40} {}", marker, record_line(a.get(i)), record_line(b.get(i)));
+        report.push_str(line.trim_end());
+        report.push('\n');
+    }
+    Some(report)
+}
+
+// rust_chip8 diff-traces [--context <n>] <a> <b>
+// Prints the first difference and returns whether the traces differ.
+pub fn diff_command(args: &[String]) -> Result<bool, String> {
+    let mut context = 5;
+    let mut paths = Vec::new();
+    let mut i = 0;
+    while i < args.len() {
+        if args[i] == "--context" {
+            context = match args.get(i + 1).and_then(|value| value.parse().ok()) {
+                Some(context) => context,
+                None => return Err("--context needs a number of instructions".to_string())
+            };
+            i += 1;
+        } else {
+            paths.push(&args[i]);
+        }
+        i += 1;
+    }
+    if paths.len() != 2 {
+        return Err("Usage: rust_chip8 diff-traces [--context <n>] <trace> <trace>".to_string());
+    }
+    let a = load_trace(paths[0])?;
+    let b = load_trace(paths[1])?;
+    match diff_report(paths[0], &a, paths[1], &b, context) {
+        Some(report) => {
+            print!("{}", report);
+            Ok(true)
+        }
+        None => {
+            println!("Traces match ({} instructions)", a.len());
+            Ok(false)
+        }
+    }
+}
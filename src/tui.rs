@@ -0,0 +1,280 @@
+use std::io::prelude::*;
+use std::io;
+use std::fs::File;
+use std::process::Command;
+use std::sync::Arc;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::mpsc;
+use std::thread;
+
+use schedule_recv;
+
+use config::Options;
+use framebuffer::Framebuffer;
+use input::{InputSource, Inputs};
+use keymap::Keymap;
+use monitor::Monitors;
+use movie::MovieSession;
+use palette::Palette;
+use {Chip8State, update_timers};
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Glyphs {
+    // One cell per two pixels stacked vertically, 64x16 cells, in color.
+    HalfBlock,
+    // One cell per 2x4 pixels, 32x8 cells, for small terminals.
+    Braille
+}
+
+pub fn parse_glyphs(text: &str) -> Result<Glyphs, String> {
+    match text {
+        "halfblock" => Ok(Glyphs::HalfBlock),
+        "braille" => Ok(Glyphs::Braille),
+        _ => Err(format!("Unknown terminal glyphs '{}', expected halfblock or braille", text))
+    }
+}
+
+// Terminals send characters rather than keys, so bindings to letters and
+// digits work in both frontends, in either case.
+pub fn key_name_for_char(c: u8) -> Option<String> {
+    if c.is_ascii_alphanumeric() {
+        Some((c.to_ascii_lowercase() as char).to_string())
+    } else {
+        None
+    }
+}
+
+// Keys typed into the terminal. Terminals only report key presses, so a
+// key is released once no repeat has arrived for the timeout.
+pub struct TerminalSource {
+    receiver: mpsc::Receiver<u8>,
+    keymap: Keymap,
+    timeout_frames: u32,
+    key_frames: [u32; 16]
+}
+
+impl InputSource for TerminalSource {
+    fn poll(&mut self, _frame: u64) -> u16 {
+        while let Ok(byte) = self.receiver.try_recv() {
+            if let Some(name) = key_name_for_char(byte) {
+                for key in self.keymap.keys_for(&name) {
+                    self.key_frames[key] = self.timeout_frames;
+                }
+            }
+        }
+        let mut mask = 0;
+        for key in 0..16 {
+            if self.key_frames[key] > 0 {
+                mask |= 1 << key;
+                self.key_frames[key] -= 1;
+            }
+        }
+        mask
+    }
+}
+
+// Puts the terminal into raw mode for as long as it is alive, and restores
+// the previous settings when dropped, including on panic.
+struct RawMode {
+    saved: String
+}
+
+impl RawMode {
+    fn enable() -> Result<RawMode, String> {
+        let saved = stty(&["-g"])?;
+        stty(&["raw", "-echo"])?;
+        Ok(RawMode { saved })
+    }
+}
+
+impl Drop for RawMode {
+    fn drop(&mut self) {
+        print!("\x1b[0m\x1b[?25h\x1b[?1049l");
+        let _ = io::stdout().flush();
+        let _ = stty(&[&self.saved]);
+    }
+}
+
+fn stty(args: &[&str]) -> Result<String, String> {
+    let tty = match File::open("/dev/tty") {
+        Ok(tty) => tty,
+        Err(e) => return Err(format!("Could not open terminal: {}", e))
+    };
+    let output = match Command::new("stty").args(args).stdin(tty).output() {
+        Ok(output) => output,
+        Err(e) => return Err(format!("Could not run stty: {}", e))
+    };
+    if !output.status.success() {
+        return Err(format!("stty failed: {}", String::from_utf8_lossy(&output.stderr).trim()));
+    }
+    Ok(String::from_utf8_lossy(&output.stdout).trim().to_string())
+}
+
+fn color_escape(layer: u8, color: [u8; 3]) -> String {
+    format!("\x1b[{};2;{};{};{}m", layer, color[0], color[1], color[2])
+}
+
+// The screen as lines of text with 24-bit color escapes, top row first.
+pub fn screen_lines(gfx: &Framebuffer, palette: &Palette, glyphs: Glyphs) -> Vec<String> {
+    let mut lines = Vec::new();
+    match glyphs {
+        Glyphs::HalfBlock => {
+            for row in 0..16 {
+                let mut line = String::new();
+                let mut current = None;
+                for x in 0..64 {
+                    let top = palette.color(gfx.pixel(x, row * 2));
+                    let bottom = palette.color(gfx.pixel(x, row * 2 + 1));
+                    if current != Some((top, bottom)) {
+                        line.push_str(&color_escape(38, top));
+                        line.push_str(&color_escape(48, bottom));
+                        current = Some((top, bottom));
+                    }
+                    line.push('\u{2580}');
+                }
+                line.push_str("\x1b[0m");
+                lines.push(line);
+            }
+        }
+        Glyphs::Braille => {
+            // Dot numbering within a braille cell, by (column, row).
+            const DOTS: [[u32; 4]; 2] = [[0x01, 0x02, 0x04, 0x40], [0x08, 0x10, 0x20, 0x80]];
+            for row in 0..8 {
+                let mut line = color_escape(38, palette.colors[1]);
+                line.push_str(&color_escape(48, palette.colors[0]));
+                for column in 0..32 {
+                    let mut bits = 0;
+                    for dx in 0..2 {
+                        for dy in 0..4 {
+                            if gfx.pixel(column * 2 + dx, row * 4 + dy) != 0 {
+                                bits |= DOTS[dx][dy];
+                            }
+                        }
+                    }
+                    line.push(::std::char::from_u32(0x2800 + bits).unwrap());
+                }
+                line.push_str("\x1b[0m");
+                lines.push(line);
+            }
+        }
+    }
+    lines
+}
+
+pub fn register_lines(state: &Chip8State) -> Vec<String> {
+    let mut lines = Vec::new();
+    lines.push(format!("PC {:04X}  I {:04X}", state.pc, state.index));
+    lines.push(format!("SP {:X}  DT {:02X}  ST {:02X}", state.stack_pointer, state.delay_timer, state.sound_timer));
+    lines.push(String::new());
+    for row in 0..8 {
+        lines.push(format!("V{:X} {:02X}    V{:X} {:02X}", row, state.v[row], row + 8, state.v[row + 8]));
+    }
+    lines.push(String::new());
+    let keys: String = (0..16).map(|key| if state.key_press[key] == 1 { format!("{:X}", key) } else { ".".to_string() }).collect();
+    lines.push(format!("Keys {}", keys));
+    if state.waiting_for_key {
+        lines.push("Waiting for key".to_string());
+    } else {
+        lines.push(String::new());
+    }
+    lines.push("Ctrl-C quits".to_string());
+    lines
+}
+
+pub fn run(options: &Options, state: &mut Chip8State, memory: &mut Vec<u8>, inputs: &mut Inputs,
+           monitors: &mut Monitors, session: &mut MovieSession) {
+    let _raw_mode = match RawMode::enable() {
+        Ok(raw_mode) => raw_mode,
+        Err(e) => {
+            eprintln!("{}", e);
+            ::std::process::exit(1);
+        }
+    };
+
+    // Reading stdin blocks, so do it on its own thread. Raw mode turns off
+    // Ctrl-C, so look for it here.
+    let (sender, receiver) = mpsc::channel();
+    let quit = Arc::new(AtomicBool::new(false));
+    let reader_quit = quit.clone();
+    thread::spawn(move || {
+        let stdin = io::stdin();
+        for byte in stdin.lock().bytes() {
+            match byte {
+                Ok(0x03) => {
+                    reader_quit.store(true, Ordering::SeqCst);
+                    break;
+                }
+                Ok(b) => {
+                    if sender.send(b).is_err() {
+                        break;
+                    }
+                }
+                Err(_) => break
+            }
+        }
+    });
+    inputs.add(Box::new(TerminalSource {
+        receiver,
+        keymap: options.keymap.clone(),
+        timeout_frames: ::std::cmp::max(1, (u64::from(options.key_timeout_ms) * 60 / 1000) as u32),
+        key_frames: [0; 16]
+    }));
+
+    let stdout = io::stdout();
+    let mut out = stdout.lock();
+    write!(out, "\x1b[?1049h\x1b[?25l\x1b[2J").unwrap();
+
+    let mut screen = Vec::new();
+    let mut last_registers = Vec::new();
+    let pixel_rows_per_line = if options.terminal_glyphs == Glyphs::HalfBlock { 2 } else { 4 };
+
+    let tick = schedule_recv::periodic_ms(1000 / 60);
+    loop {
+        if quit.load(Ordering::SeqCst) {
+            return;
+        }
+        inputs.poll(session.frame(), &mut state.key_press);
+        session.begin_frame(&mut state.key_press);
+        monitors.run(state, memory, options.cycles_per_frame, |_| ());
+        update_timers(state);
+        monitors.end_frame();
+        if let Err(e) = session.end_frame(&state.gfx) {
+            drop(out);
+            drop(_raw_mode);
+            eprintln!("{}", e);
+            ::std::process::exit(1);
+        }
+
+        // Only rewrite lines that changed, as terminals can be slow to
+        // draw, or at the other end of a network.
+        let spans = state.gfx.take_dirty();
+        if !spans.is_empty() {
+            screen = screen_lines(&state.gfx, &options.palette, options.terminal_glyphs);
+        }
+        let registers = register_lines(state);
+        let screen_width = if options.terminal_glyphs == Glyphs::HalfBlock { 64 } else { 32 };
+        let mut frame = String::new();
+        for row in 0..::std::cmp::max(screen.len(), registers.len()) {
+            let drawn = spans.iter().any(|span| span.y / pixel_rows_per_line == row);
+            if !drawn && registers.get(row) == last_registers.get(row) {
+                continue;
+            }
+            frame.push_str(&format!("\x1b[{};1H", row + 1));
+            match screen.get(row) {
+                Some(line) => frame.push_str(line),
+                None => frame.push_str(&" ".repeat(screen_width))
+            }
+            frame.push_str("  ");
+            if let Some(line) = registers.get(row) {
+                frame.push_str(line);
+            }
+            frame.push_str("\x1b[K");
+        }
+        last_registers = registers;
+        if !frame.is_empty() {
+            out.write_all(frame.as_bytes()).unwrap();
+            out.flush().unwrap();
+        }
+        tick.recv().unwrap();
+    }
+}